@@ -0,0 +1,429 @@
+use crate::ast::{
+  Arity, Atom, Expr, Key, Keyword, List, Map, Native, Port, Symbol, Vector,
+};
+use crate::eval::EvalError;
+
+// Converts an expression into a Rust value, failing if it has the wrong type.
+pub trait FromExpr: Sized {
+  fn from_expr(expr: Expr) -> Result<Self, EvalError>;
+}
+
+pub trait IntoExpr {
+  fn into_expr(self) -> Expr;
+}
+
+// Converts the return value of a Rust function registered as a native.
+pub trait IntoResult {
+  fn into_result(self) -> Result<Expr, EvalError>;
+}
+
+impl<T> IntoResult for T
+where
+  T: IntoExpr,
+{
+  fn into_result(self) -> Result<Expr, EvalError> {
+    Ok(self.into_expr())
+  }
+}
+
+impl<T> IntoResult for Result<T, EvalError>
+where
+  T: IntoExpr,
+{
+  fn into_result(self) -> Result<Expr, EvalError> {
+    self.map(IntoExpr::into_expr)
+  }
+}
+
+impl FromExpr for Expr {
+  fn from_expr(expr: Expr) -> Result<Expr, EvalError> {
+    Ok(expr)
+  }
+}
+
+impl IntoExpr for Expr {
+  fn into_expr(self) -> Expr {
+    self
+  }
+}
+
+impl FromExpr for List {
+  fn from_expr(expr: Expr) -> Result<List, EvalError> {
+    match expr {
+      Expr::List(list) => Ok(list),
+      _ => Err(EvalError::InvalidType),
+    }
+  }
+}
+
+impl IntoExpr for List {
+  fn into_expr(self) -> Expr {
+    Expr::List(self)
+  }
+}
+
+impl FromExpr for Atom {
+  fn from_expr(expr: Expr) -> Result<Atom, EvalError> {
+    match expr {
+      Expr::Atom(atom) => Ok(atom),
+      _ => Err(EvalError::InvalidType),
+    }
+  }
+}
+
+impl IntoExpr for Atom {
+  fn into_expr(self) -> Expr {
+    Expr::Atom(self)
+  }
+}
+
+impl FromExpr for f64 {
+  fn from_expr(expr: Expr) -> Result<f64, EvalError> {
+    match expr {
+      Expr::Atom(Atom::Number(number)) => Ok(number),
+      _ => Err(EvalError::InvalidType),
+    }
+  }
+}
+
+impl IntoExpr for f64 {
+  fn into_expr(self) -> Expr {
+    Expr::Atom(Atom::Number(self))
+  }
+}
+
+impl FromExpr for f32 {
+  fn from_expr(expr: Expr) -> Result<f32, EvalError> {
+    f64::from_expr(expr).map(|number| number as f32)
+  }
+}
+
+impl IntoExpr for f32 {
+  fn into_expr(self) -> Expr {
+    Expr::Atom(Atom::Number(self as f64))
+  }
+}
+
+// Integers only accept whole numbers that fit in the type.
+macro_rules! impl_integer {
+  ($($integer:ty),*) => {
+    $(
+      impl FromExpr for $integer {
+        fn from_expr(expr: Expr) -> Result<$integer, EvalError> {
+          let number = f64::from_expr(expr)?;
+
+          if number.fract() != 0.0
+            || number < <$integer>::MIN as f64
+            || number > <$integer>::MAX as f64
+          {
+            return Err(EvalError::InvalidType);
+          }
+
+          Ok(number as $integer)
+        }
+      }
+
+      impl IntoExpr for $integer {
+        fn into_expr(self) -> Expr {
+          Expr::Atom(Atom::Number(self as f64))
+        }
+      }
+    )*
+  };
+}
+
+impl_integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl FromExpr for bool {
+  fn from_expr(expr: Expr) -> Result<bool, EvalError> {
+    match expr {
+      Expr::Atom(Atom::Bool(bool)) => Ok(bool),
+      _ => Err(EvalError::InvalidType),
+    }
+  }
+}
+
+impl IntoExpr for bool {
+  fn into_expr(self) -> Expr {
+    Expr::Atom(Atom::Bool(self))
+  }
+}
+
+impl FromExpr for String {
+  fn from_expr(expr: Expr) -> Result<String, EvalError> {
+    match expr {
+      Expr::Atom(Atom::String(string)) => Ok(string),
+      _ => Err(EvalError::InvalidType),
+    }
+  }
+}
+
+impl IntoExpr for String {
+  fn into_expr(self) -> Expr {
+    Expr::Atom(Atom::String(self))
+  }
+}
+
+impl FromExpr for char {
+  fn from_expr(expr: Expr) -> Result<char, EvalError> {
+    match expr {
+      Expr::Atom(Atom::Char(char)) => Ok(char),
+      _ => Err(EvalError::InvalidType),
+    }
+  }
+}
+
+impl IntoExpr for char {
+  fn into_expr(self) -> Expr {
+    Expr::Atom(Atom::Char(self))
+  }
+}
+
+impl IntoExpr for &str {
+  fn into_expr(self) -> Expr {
+    Expr::Atom(Atom::String(self.to_string()))
+  }
+}
+
+impl FromExpr for Symbol {
+  fn from_expr(expr: Expr) -> Result<Symbol, EvalError> {
+    match expr {
+      Expr::Atom(Atom::Symbol(symbol)) => Ok(symbol),
+      _ => Err(EvalError::InvalidType),
+    }
+  }
+}
+
+impl IntoExpr for Symbol {
+  fn into_expr(self) -> Expr {
+    Expr::Atom(Atom::Symbol(self))
+  }
+}
+
+impl FromExpr for Keyword {
+  fn from_expr(expr: Expr) -> Result<Keyword, EvalError> {
+    match expr {
+      Expr::Atom(Atom::Keyword(keyword)) => Ok(keyword),
+      _ => Err(EvalError::InvalidType),
+    }
+  }
+}
+
+impl IntoExpr for Keyword {
+  fn into_expr(self) -> Expr {
+    Expr::Atom(Atom::Keyword(self))
+  }
+}
+
+impl FromExpr for Vector {
+  fn from_expr(expr: Expr) -> Result<Vector, EvalError> {
+    match expr {
+      Expr::Atom(Atom::Vector(vector)) => Ok(vector),
+      _ => Err(EvalError::InvalidType),
+    }
+  }
+}
+
+impl IntoExpr for Vector {
+  fn into_expr(self) -> Expr {
+    Expr::Atom(Atom::Vector(self))
+  }
+}
+
+impl FromExpr for Map {
+  fn from_expr(expr: Expr) -> Result<Map, EvalError> {
+    match expr {
+      Expr::Atom(Atom::Map(map)) => Ok(map),
+      _ => Err(EvalError::InvalidType),
+    }
+  }
+}
+
+impl IntoExpr for Map {
+  fn into_expr(self) -> Expr {
+    Expr::Atom(Atom::Map(self))
+  }
+}
+
+// NaN isn't equal to itself, so it can't be used as a key.
+impl FromExpr for Key {
+  fn from_expr(expr: Expr) -> Result<Key, EvalError> {
+    match expr {
+      Expr::Atom(Atom::Number(number)) if !number.is_nan() => {
+        // Adding zero turns negative zero into zero.
+        Ok(Key::Number((number + 0.0).to_bits()))
+      }
+      Expr::Atom(Atom::Bool(bool)) => Ok(Key::Bool(bool)),
+      Expr::Atom(Atom::String(string)) => Ok(Key::String(string)),
+      Expr::Atom(Atom::Symbol(symbol)) => Ok(Key::Symbol(symbol)),
+      Expr::Atom(Atom::Keyword(keyword)) => Ok(Key::Keyword(keyword)),
+      Expr::Atom(Atom::Char(char)) => Ok(Key::Char(char)),
+      Expr::Atom(Atom::Special(special)) => Ok(Key::Special(special)),
+      Expr::List(list) => list
+        .into_iter()
+        .map(Key::from_expr)
+        .collect::<Result<_, _>>()
+        .map(Key::List),
+      _ => Err(EvalError::InvalidKey),
+    }
+  }
+}
+
+impl IntoExpr for Key {
+  fn into_expr(self) -> Expr {
+    match self {
+      Key::Number(bits) => Expr::Atom(Atom::Number(f64::from_bits(bits))),
+      Key::Bool(bool) => Expr::Atom(Atom::Bool(bool)),
+      Key::String(string) => Expr::Atom(Atom::String(string)),
+      Key::Symbol(symbol) => Expr::Atom(Atom::Symbol(symbol)),
+      Key::Keyword(keyword) => Expr::Atom(Atom::Keyword(keyword)),
+      Key::Char(char) => Expr::Atom(Atom::Char(char)),
+      Key::Special(special) => Expr::Atom(Atom::Special(special)),
+      Key::List(keys) => keys.into_expr(),
+    }
+  }
+}
+
+impl FromExpr for Port {
+  fn from_expr(expr: Expr) -> Result<Port, EvalError> {
+    match expr {
+      Expr::Atom(Atom::Port(port)) => Ok(port),
+      _ => Err(EvalError::InvalidType),
+    }
+  }
+}
+
+impl IntoExpr for Port {
+  fn into_expr(self) -> Expr {
+    Expr::Atom(Atom::Port(self))
+  }
+}
+
+impl<T> FromExpr for Vec<T>
+where
+  T: FromExpr,
+{
+  fn from_expr(expr: Expr) -> Result<Vec<T>, EvalError> {
+    List::from_expr(expr)?
+      .into_iter()
+      .map(T::from_expr)
+      .collect()
+  }
+}
+
+impl<T> IntoExpr for Vec<T>
+where
+  T: IntoExpr,
+{
+  fn into_expr(self) -> Expr {
+    Expr::List(
+      self
+        .into_iter()
+        .rev()
+        .fold(List::Nil, |list, item| List::cons(item.into_expr(), list)),
+    )
+  }
+}
+
+// False stands in for a missing value, like it does for natives such as
+// `getenv`.
+impl<T> FromExpr for Option<T>
+where
+  T: FromExpr,
+{
+  fn from_expr(expr: Expr) -> Result<Option<T>, EvalError> {
+    match expr {
+      Expr::Atom(Atom::Bool(false)) => Ok(None),
+      expr => T::from_expr(expr).map(Some),
+    }
+  }
+}
+
+impl<T> IntoExpr for Option<T>
+where
+  T: IntoExpr,
+{
+  fn into_expr(self) -> Expr {
+    match self {
+      Some(value) => value.into_expr(),
+      None => Expr::Atom(Atom::Bool(false)),
+    }
+  }
+}
+
+impl IntoExpr for () {
+  fn into_expr(self) -> Expr {
+    Expr::List(List::Nil)
+  }
+}
+
+// Tuples are converted from and into lists of the same length.
+macro_rules! impl_tuple {
+  ($($name:ident),*) => {
+    impl<$($name),*> FromExpr for ($($name,)*)
+    where
+      $($name: FromExpr,)*
+    {
+      fn from_expr(expr: Expr) -> Result<($($name,)*), EvalError> {
+        let mut items = List::from_expr(expr)?.into_iter();
+
+        let tuple = ($(
+          $name::from_expr(items.next().ok_or(EvalError::InvalidType)?)?,
+        )*);
+
+        match items.next() {
+          Some(_) => Err(EvalError::InvalidType),
+          None => Ok(tuple),
+        }
+      }
+    }
+
+    impl<$($name),*> IntoExpr for ($($name,)*)
+    where
+      $($name: IntoExpr,)*
+    {
+      #[allow(non_snake_case)]
+      fn into_expr(self) -> Expr {
+        let ($($name,)*) = self;
+        vec![$($name.into_expr()),*].into_expr()
+      }
+    }
+  };
+}
+
+impl_tuple!(A);
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
+
+// A Rust function that can be registered as a native, with its arity and
+// argument types checked automatically.
+pub trait IntoNative<Args> {
+  fn into_native(self, name: &str) -> Native;
+}
+
+macro_rules! impl_into_native {
+  ($count:expr $(, $name:ident)*) => {
+    impl<F, R, $($name),*> IntoNative<($($name,)*)> for F
+    where
+      F: Fn($($name),*) -> R + 'static,
+      R: IntoResult,
+      $($name: FromExpr,)*
+    {
+      #[allow(non_snake_case, unused_mut, unused_variables)]
+      fn into_native(self, name: &str) -> Native {
+        Native::new(name, Arity::Exact($count), move |_, arguments| {
+          let mut arguments = arguments.into_iter();
+          $(let $name = $name::from_expr(arguments.next().unwrap())?;)*
+          self($($name),*).into_result()
+        })
+      }
+    }
+  };
+}
+
+impl_into_native!(0);
+impl_into_native!(1, A);
+impl_into_native!(2, A, B);
+impl_into_native!(3, A, B, C);
+impl_into_native!(4, A, B, C, D);
@@ -2,7 +2,7 @@ use std::fmt;
 use std::iter::Iterator;
 use std::rc::Rc;
 
-use super::Expr;
+use super::{record_allocation, Expr, Location};
 
 #[derive(Clone, Debug)]
 pub enum List {
@@ -14,41 +14,67 @@ pub enum List {
 pub struct Node {
   pub head: Expr,
   pub tail: List,
+  pub location: Option<Location>,
 }
 
 impl List {
   pub fn cons(head: Expr, tail: List) -> List {
     use List::*;
 
-    let node = Node { head, tail };
+    record_allocation();
+
+    let node = Node {
+      head,
+      tail,
+      location: None,
+    };
     Cons(Rc::new(node))
   }
 
-  pub fn get(&self, index: usize) -> Option<&Expr> {
+  pub fn cons_at(head: Expr, tail: List, location: Location) -> List {
     use List::*;
 
-    let node = match self {
-      Cons(node) => node,
-      Nil => return None,
+    record_allocation();
+
+    let node = Node {
+      head,
+      tail,
+      location: Some(location),
     };
+    Cons(Rc::new(node))
+  }
 
-    if index == 0 {
-      Some(&node.head)
-    } else {
-      node.tail.get(index - 1)
-    }
+  pub fn get(&self, index: usize) -> Option<&Expr> {
+    self.nodes().nth(index).map(|node| &node.head)
   }
 
   pub fn len(&self) -> usize {
-    use List::*;
+    self.nodes().count()
+  }
 
-    match self {
-      Cons(node) => 1 + node.tail.len(),
-      Nil => 0,
-    }
+  // Walks the nodes of the list without cloning them.
+  fn nodes(&self) -> impl Iterator<Item = &Node> {
+    let mut list = self;
+
+    std::iter::from_fn(move || match list {
+      List::Cons(node) => {
+        list = &node.tail;
+        Some(node.as_ref())
+      }
+      List::Nil => None,
+    })
+  }
+
+  pub fn is_empty(&self) -> bool {
+    matches!(self, List::Nil)
   }
+}
+
+impl IntoIterator for List {
+  type Item = Expr;
+  type IntoIter = IntoIter;
 
-  pub fn into_iter(self) -> IntoIter {
+  fn into_iter(self) -> IntoIter {
     IntoIter(self)
   }
 }
@@ -58,7 +84,7 @@ impl PartialEq for List {
     use List::*;
 
     match (self, other) {
-      (Cons(left), Cons(right)) => Rc::ptr_eq(&left, &right),
+      (Cons(left), Cons(right)) => Rc::ptr_eq(left, right),
       (Nil, Nil) => true,
       _ => false,
     }
@@ -0,0 +1,349 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use super::{record_allocation, Expr, IntoExpr, Keyword, Special, Symbol};
+
+// The bits of a hash used at each level of the trie.
+const BITS: u32 = 5;
+const MASK: u64 = (1 << BITS) - 1;
+
+// A value that can be used as a key in a map. Lists are compared by their
+// items rather than by identity.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Key {
+  Bool(bool),
+  // Stored as bits, with negative zero normalized to zero.
+  Number(u64),
+  String(String),
+  Symbol(Symbol),
+  Keyword(Keyword),
+  Char(char),
+  Special(Special),
+  List(Vec<Key>),
+}
+
+// A map as it is written in code, with its entries in order. Keys are only
+// known once they are evaluated, as forms that look the same can evaluate to
+// different keys.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapLiteral {
+  entries: Rc<Vec<(Expr, Expr)>>,
+}
+
+impl MapLiteral {
+  pub fn new(entries: Vec<(Expr, Expr)>) -> MapLiteral {
+    MapLiteral {
+      entries: Rc::new(entries),
+    }
+  }
+
+  pub fn entries(&self) -> &[(Expr, Expr)] {
+    &self.entries
+  }
+}
+
+impl Key {
+  fn hash(&self) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    Hash::hash(self, &mut hasher);
+    hasher.finish()
+  }
+}
+
+// An immutable hash map. Updates return a new map which shares most of its
+// structure with the original, like `cons` does with lists.
+#[derive(Clone, Default)]
+pub struct Map {
+  root: Option<Rc<MapNode>>,
+  len: usize,
+}
+
+pub(crate) enum MapNode {
+  Branch {
+    bitmap: u32,
+    children: Vec<Rc<MapNode>>,
+  },
+  // Entries whose keys all have the same hash.
+  Leaf {
+    hash: u64,
+    entries: Vec<(Key, Expr)>,
+  },
+}
+
+impl Map {
+  pub fn new() -> Map {
+    Map::default()
+  }
+
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  pub fn get(&self, key: &Key) -> Option<&Expr> {
+    let hash = key.hash();
+    let mut node = self.root.as_ref()?;
+    let mut shift = 0;
+
+    loop {
+      match node.as_ref() {
+        MapNode::Branch { bitmap, children } => {
+          let bit = 1 << ((hash >> shift) & MASK);
+          if bitmap & bit == 0 {
+            return None;
+          }
+          node = &children[(bitmap & (bit - 1)).count_ones() as usize];
+          shift += BITS;
+        }
+        MapNode::Leaf { entries, .. } => {
+          return entries
+            .iter()
+            .find(|(entry, _)| entry == key)
+            .map(|(_, value)| value);
+        }
+      }
+    }
+  }
+
+  pub fn contains(&self, key: &Key) -> bool {
+    self.get(key).is_some()
+  }
+
+  pub fn insert(&self, key: Key, value: Expr) -> Map {
+    record_allocation();
+
+    let hash = key.hash();
+    let (root, added) = match &self.root {
+      Some(root) => insert(root, hash, 0, key, value),
+      None => (Rc::new(leaf(hash, key, value)), true),
+    };
+
+    Map {
+      root: Some(root),
+      len: if added { self.len + 1 } else { self.len },
+    }
+  }
+
+  pub fn remove(&self, key: &Key) -> Map {
+    let root = match &self.root {
+      Some(root) => root,
+      None => return self.clone(),
+    };
+
+    match remove(root, key.hash(), 0, key) {
+      Some(root) => {
+        record_allocation();
+
+        Map {
+          root,
+          len: self.len - 1,
+        }
+      }
+      None => self.clone(),
+    }
+  }
+
+  // Returns the entries of the map, in an order that depends only on their
+  // keys.
+  pub fn entries(&self) -> Vec<(Key, Expr)> {
+    let mut entries = Vec::with_capacity(self.len);
+    let mut stack: Vec<&MapNode> = self.root.iter().map(Rc::as_ref).collect();
+
+    while let Some(node) = stack.pop() {
+      match node {
+        MapNode::Branch { children, .. } => {
+          stack.extend(children.iter().rev().map(Rc::as_ref));
+        }
+        MapNode::Leaf {
+          entries: leaf_entries,
+          ..
+        } => entries.extend(leaf_entries.iter().cloned()),
+      }
+    }
+
+    entries
+  }
+
+  pub(crate) fn root(&self) -> Option<&Rc<MapNode>> {
+    self.root.as_ref()
+  }
+}
+
+fn leaf(hash: u64, key: Key, value: Expr) -> MapNode {
+  MapNode::Leaf {
+    hash,
+    entries: vec![(key, value)],
+  }
+}
+
+fn insert(
+  node: &Rc<MapNode>,
+  hash: u64,
+  shift: u32,
+  key: Key,
+  value: Expr,
+) -> (Rc<MapNode>, bool) {
+  match node.as_ref() {
+    MapNode::Branch { bitmap, children } => {
+      let bit = 1 << ((hash >> shift) & MASK);
+      let index = (bitmap & (bit - 1)).count_ones() as usize;
+      let mut children = children.clone();
+
+      let added = if bitmap & bit == 0 {
+        children.insert(index, Rc::new(leaf(hash, key, value)));
+        true
+      } else {
+        let (child, added) =
+          insert(&children[index], hash, shift + BITS, key, value);
+        children[index] = child;
+        added
+      };
+
+      let node = MapNode::Branch {
+        bitmap: bitmap | bit,
+        children,
+      };
+      (Rc::new(node), added)
+    }
+    MapNode::Leaf {
+      hash: leaf_hash,
+      entries,
+    } if *leaf_hash == hash => {
+      let mut entries = entries.clone();
+
+      let added = match entries.iter_mut().find(|(entry, _)| *entry == key) {
+        Some(entry) => {
+          entry.1 = value;
+          false
+        }
+        None => {
+          entries.push((key, value));
+          true
+        }
+      };
+
+      (Rc::new(MapNode::Leaf { hash, entries }), added)
+    }
+    MapNode::Leaf {
+      hash: leaf_hash, ..
+    } => {
+      // The hashes differ, so the leaf is pushed down into a branch where
+      // they can be told apart.
+      let bit = 1 << ((leaf_hash >> shift) & MASK);
+      let branch = Rc::new(MapNode::Branch {
+        bitmap: bit,
+        children: vec![node.clone()],
+      });
+
+      insert(&branch, hash, shift, key, value)
+    }
+  }
+}
+
+// Returns the node without the key, or None if it wasn't found. An empty node
+// is returned as Some(None).
+fn remove(
+  node: &Rc<MapNode>,
+  hash: u64,
+  shift: u32,
+  key: &Key,
+) -> Option<Option<Rc<MapNode>>> {
+  match node.as_ref() {
+    MapNode::Branch { bitmap, children } => {
+      let bit = 1 << ((hash >> shift) & MASK);
+      if bitmap & bit == 0 {
+        return None;
+      }
+
+      let index = (bitmap & (bit - 1)).count_ones() as usize;
+      let child = remove(&children[index], hash, shift + BITS, key)?;
+
+      let mut children = children.clone();
+      let bitmap = match child {
+        Some(child) => {
+          children[index] = child;
+          *bitmap
+        }
+        None => {
+          children.remove(index);
+          bitmap & !bit
+        }
+      };
+
+      // Branches left with a single leaf are replaced by the leaf.
+      let node = match children.as_slice() {
+        [] => None,
+        [child] if matches!(child.as_ref(), MapNode::Leaf { .. }) => {
+          Some(child.clone())
+        }
+        _ => Some(Rc::new(MapNode::Branch { bitmap, children })),
+      };
+
+      Some(node)
+    }
+    MapNode::Leaf { hash, entries } => {
+      let index = entries.iter().position(|(entry, _)| entry == key)?;
+
+      let mut entries = entries.clone();
+      entries.remove(index);
+
+      if entries.is_empty() {
+        Some(None)
+      } else {
+        let hash = *hash;
+        Some(Some(Rc::new(MapNode::Leaf { hash, entries })))
+      }
+    }
+  }
+}
+
+impl PartialEq for Map {
+  fn eq(&self, other: &Map) -> bool {
+    match (&self.root, &other.root) {
+      (Some(left), Some(right)) => Rc::ptr_eq(left, right),
+      (None, None) => true,
+      _ => false,
+    }
+  }
+}
+
+impl fmt::Display for Map {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{{{}}}",
+      self
+        .entries()
+        .iter()
+        .map(|(key, value)| format!("{} {}", key.clone().into_expr(), value))
+        .collect::<Vec<String>>()
+        .join(" ")
+    )
+  }
+}
+
+impl fmt::Display for MapLiteral {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{{{}}}",
+      self
+        .entries
+        .iter()
+        .map(|(key, value)| format!("{} {}", key, value))
+        .collect::<Vec<String>>()
+        .join(" ")
+    )
+  }
+}
+
+impl fmt::Debug for Map {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Map {:?}", self.entries())
+  }
+}
@@ -1,3 +1,4 @@
+use std::cell::{Cell, RefCell};
 use std::fmt;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
@@ -5,11 +6,22 @@ use std::sync::{Arc, Mutex};
 use lazy_static::lazy_static;
 
 use crate::env::Frame;
-use crate::eval::EvalError;
+use crate::eval::{EvalError, Evaluator};
+use crate::read;
+use crate::resolve::{Code, Lambda};
 
+pub use self::convert::{FromExpr, IntoExpr, IntoNative, IntoResult};
 pub use self::list::{List, Node};
+pub(crate) use self::map::MapNode;
+pub use self::map::{Key, Map, MapLiteral};
+pub use self::port::Port;
+pub use self::vector::Vector;
 
+pub mod convert;
 pub mod list;
+pub mod map;
+pub mod port;
+pub mod vector;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Expr {
@@ -18,13 +30,42 @@ pub enum Expr {
 }
 
 impl Expr {
-  pub fn is_truthy(&self) -> bool {
-    if let Expr::List(List::Nil) = self {
-      false
-    } else {
-      true
+  // Returns the human form of the expression, where strings and characters
+  // are written as they are rather than as literals.
+  pub fn to_display_string(&self) -> String {
+    fn join(exprs: Vec<Expr>) -> String {
+      exprs
+        .iter()
+        .map(Expr::to_display_string)
+        .collect::<Vec<String>>()
+        .join(" ")
+    }
+
+    match self {
+      Expr::Atom(Atom::String(string)) => string.clone(),
+      Expr::Atom(Atom::Char(char)) => char.to_string(),
+      Expr::Atom(Atom::Vector(vector)) => {
+        format!("[{}]", join(vector.to_vec()))
+      }
+      Expr::Atom(Atom::Map(map)) => {
+        let exprs = map
+          .entries()
+          .into_iter()
+          .flat_map(|(key, value)| vec![key.into_expr(), value])
+          .collect();
+        format!("{{{}}}", join(exprs))
+      }
+      Expr::List(list) => {
+        format!("({})", join(list.clone().into_iter().collect()))
+      }
+      expr => expr.to_string(),
     }
   }
+
+  // Only false and the empty list are falsy.
+  pub fn is_truthy(&self) -> bool {
+    !matches!(self, Expr::Atom(Atom::Bool(false)) | Expr::List(List::Nil))
+  }
 }
 
 impl fmt::Display for Expr {
@@ -40,13 +81,24 @@ impl fmt::Display for Expr {
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Atom {
+  Bool(bool),
   Number(f64),
   Symbol(Symbol),
+  Keyword(Keyword),
+  Char(char),
   String(String),
+  Vector(Vector),
+  Map(Map),
+  // A map literal in code, which evaluates to a map.
+  MapLiteral(MapLiteral),
   Function(Function),
   Macro(Macro),
   Special(Special),
   Native(Native),
+  Environment(Frame),
+  Port(Port),
+  // Returned when reading past the end of a port.
+  Eof,
 }
 
 impl fmt::Display for Atom {
@@ -54,26 +106,101 @@ impl fmt::Display for Atom {
     use Atom::*;
 
     match self {
+      Bool(true) => write!(f, "#t"),
+      Bool(false) => write!(f, "#f"),
       Number(number) => write!(f, "{}", number),
       Symbol(symbol) => write!(f, "{}", symbol),
-      String(string) => write!(f, "\"{}\"", string),
+      Keyword(keyword) => write!(f, "{}", keyword),
+      Char(char) => write_char(f, *char),
+      String(string) => write_string(f, string),
+      Vector(vector) => write!(f, "{}", vector),
+      Map(map) => write!(f, "{}", map),
+      MapLiteral(literal) => write!(f, "{}", literal),
       Function(function) => write!(f, "{}", function),
       Macro(macr) => write!(f, "{}", macr),
       Special(special) => write!(f, "{}", special),
       Native(native) => write!(f, "{}", native),
+      Environment(_) => write!(f, "Environment"),
+      Port(port) => write!(f, "{}", port),
+      Eof => write!(f, "End of File"),
     }
   }
 }
 
+// Writes a string with quotes, escaped so that it reads back the same.
+fn write_string(f: &mut fmt::Formatter<'_>, string: &str) -> fmt::Result {
+  write!(f, "\"")?;
+
+  for char in string.chars() {
+    match char {
+      '"' => write!(f, "\\\"")?,
+      '\\' => write!(f, "\\\\")?,
+      '\n' => write!(f, "\\n")?,
+      '\t' => write!(f, "\\t")?,
+      '\r' => write!(f, "\\r")?,
+      '\0' => write!(f, "\\0")?,
+      char if char.is_control() => write!(f, "\\u{{{:x}}}", char as u32)?,
+      char => write!(f, "{}", char)?,
+    }
+  }
+
+  write!(f, "\"")
+}
+
+// Writes a character literal, using names for those that are invisible.
+fn write_char(f: &mut fmt::Formatter<'_>, char: char) -> fmt::Result {
+  match read::char_name(char) {
+    Some(name) => write!(f, "#\\{}", name),
+    None if char.is_control() => write!(f, "#\\x{:x}", char as u32),
+    None => write!(f, "#\\{}", char),
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+  pub line: usize,
+  pub column: usize,
+}
+
+impl fmt::Display for Location {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.line, self.column)
+  }
+}
+
 lazy_static! {
   static ref SYMBOLS: Mutex<Vec<Symbol>> = Mutex::new(Vec::new());
 }
 
-lazy_static! {
-  pub static ref SYMBOL_TRUE: Symbol = Symbol::new("true");
+thread_local! {
+  // Lists, frames and closures allocated on this thread so far.
+  static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+  // Counter of the evaluator running on this thread, if any.
+  static COUNTER: RefCell<Option<Rc<Cell<u64>>>> = const { RefCell::new(None) };
+}
+
+pub fn allocations() -> u64 {
+  ALLOCATIONS.with(Cell::get)
 }
 
-#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub(crate) fn record_allocation() {
+  ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+  COUNTER.with(|counter| {
+    if let Some(counter) = counter.borrow().as_ref() {
+      counter.set(counter.get() + 1);
+    }
+  });
+}
+
+// Counts allocations on this thread against the counter too, returning the
+// counter it replaces.
+pub(crate) fn set_allocation_counter(
+  counter: Option<Rc<Cell<u64>>>,
+) -> Option<Rc<Cell<u64>>> {
+  COUNTER.with(|current| current.replace(counter))
+}
+
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Symbol {
   inner: Arc<String>,
 }
@@ -106,9 +233,51 @@ impl Symbol {
   }
 }
 
+// Symbols that wouldn't read back as themselves are written between bars.
 impl fmt::Display for Symbol {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "{}", self.inner.as_str())
+    if read::is_plain_symbol(self.as_str()) {
+      return write!(f, "{}", self.as_str());
+    }
+
+    write!(f, "|")?;
+    for char in self.as_str().chars() {
+      match char {
+        '|' | '\\' => write!(f, "\\{}", char)?,
+        char => write!(f, "{}", char)?,
+      }
+    }
+    write!(f, "|")
+  }
+}
+
+// A name that evaluates to itself, written like `:name`. Keywords are
+// interned along with symbols, so comparing them is cheap.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Keyword {
+  name: Symbol,
+}
+
+impl Keyword {
+  pub fn new<S>(name: S) -> Keyword
+  where
+    S: Into<String>,
+  {
+    Keyword {
+      name: Symbol::new(name),
+    }
+  }
+
+  pub fn as_str(&self) -> &str {
+    self.name.as_str()
+  }
+}
+
+// Names that aren't plain symbols, including the empty name, are written
+// with bars, like `:|two words|` and `:||`.
+impl fmt::Display for Keyword {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, ":{}", self.name)
   }
 }
 
@@ -119,18 +288,15 @@ pub struct Function {
 
 pub struct FunctionInner {
   pub frame: Frame,
-  pub parameters: Vec<Symbol>,
-  pub body: Expr,
+  pub lambda: Rc<Lambda>,
 }
 
 impl Function {
-  pub fn new(frame: Frame, parameters: Vec<Symbol>, body: Expr) -> Function {
+  pub fn new(frame: Frame, lambda: Rc<Lambda>) -> Function {
+    record_allocation();
+
     Function {
-      inner: Rc::new(FunctionInner {
-        frame,
-        parameters,
-        body,
-      }),
+      inner: Rc::new(FunctionInner { frame, lambda }),
     }
   }
 
@@ -138,12 +304,24 @@ impl Function {
     &self.inner.frame
   }
 
+  pub fn lambda(&self) -> &Lambda {
+    &self.inner.lambda
+  }
+
   pub fn parameters(&self) -> &[Symbol] {
-    &self.inner.parameters
+    &self.inner.lambda.parameters
   }
 
-  pub fn body(&self) -> &Expr {
-    &self.inner.body
+  pub fn body(&self) -> &Code {
+    &self.inner.lambda.body
+  }
+
+  pub(crate) fn id(&self) -> usize {
+    Rc::as_ptr(&self.inner) as usize
+  }
+
+  pub(crate) fn references(&self) -> usize {
+    Rc::strong_count(&self.inner)
   }
 }
 
@@ -164,7 +342,8 @@ impl fmt::Debug for Function {
     write!(
       f,
       "Function {{ parameters: {:?}, body: {:?} }}",
-      self.inner.parameters, self.inner.body
+      self.parameters(),
+      self.body()
     )
   }
 }
@@ -175,23 +354,41 @@ pub struct Macro {
 }
 
 pub struct MacroInner {
-  pub parameter: Symbol,
-  pub body: Expr,
+  pub frame: Frame,
+  pub lambda: Rc<Lambda>,
 }
 
 impl Macro {
-  pub fn new(parameter: Symbol, body: Expr) -> Macro {
+  pub fn new(frame: Frame, lambda: Rc<Lambda>) -> Macro {
+    record_allocation();
+
     Macro {
-      inner: Rc::new(MacroInner { parameter, body }),
+      inner: Rc::new(MacroInner { frame, lambda }),
     }
   }
 
+  pub fn frame(&self) -> &Frame {
+    &self.inner.frame
+  }
+
+  pub fn lambda(&self) -> &Lambda {
+    &self.inner.lambda
+  }
+
   pub fn parameter(&self) -> &Symbol {
-    &self.inner.parameter
+    &self.inner.lambda.parameters[0]
   }
 
-  pub fn body(&self) -> &Expr {
-    &self.inner.body
+  pub fn body(&self) -> &Code {
+    &self.inner.lambda.body
+  }
+
+  pub(crate) fn id(&self) -> usize {
+    Rc::as_ptr(&self.inner) as usize
+  }
+
+  pub(crate) fn references(&self) -> usize {
+    Rc::strong_count(&self.inner)
   }
 }
 
@@ -212,12 +409,13 @@ impl fmt::Debug for Macro {
     write!(
       f,
       "Macro {{ parameter: {:?}, body: {:?} }}",
-      self.inner.parameter, self.inner.body
+      self.parameter(),
+      self.body()
     )
   }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Special {
   Begin,
   Define,
@@ -226,6 +424,9 @@ pub enum Special {
   If,
   Quote,
   Operator(Operator),
+  Import,
+  Require,
+  Export,
 }
 
 impl fmt::Display for Special {
@@ -234,7 +435,7 @@ impl fmt::Display for Special {
   }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Operator {
   Add,
   Sub,
@@ -246,26 +447,91 @@ pub enum Operator {
   Eq,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arity {
+  Exact(usize),
+  AtLeast(usize),
+  Range(usize, usize),
+}
+
+impl Arity {
+  pub fn accepts(&self, count: usize) -> bool {
+    use Arity::*;
+
+    match *self {
+      Exact(exact) => count == exact,
+      AtLeast(min) => count >= min,
+      Range(min, max) => count >= min && count <= max,
+    }
+  }
+}
+
+impl fmt::Display for Arity {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    use Arity::*;
+
+    match self {
+      Exact(exact) => write!(f, "{}", exact),
+      AtLeast(min) => write!(f, "at least {}", min),
+      Range(min, max) => write!(f, "{} to {}", min, max),
+    }
+  }
+}
+
 #[derive(Clone)]
 pub struct Native {
-  inner: Rc<NativeFn>,
+  name: Symbol,
+  arity: Arity,
+  function: Rc<NativeFn>,
 }
 
 impl Native {
-  pub fn new(native: NativeFn) -> Native {
+  pub fn new<S, F>(name: S, arity: Arity, function: F) -> Native
+  where
+    S: Into<String>,
+    F: Fn(&mut Evaluator, Vec<Expr>) -> Result<Expr, EvalError> + 'static,
+  {
     Native {
-      inner: Rc::new(native),
+      name: Symbol::new(name),
+      arity,
+      function: Rc::new(function),
     }
   }
 
-  pub fn call(&self, arguments: Vec<Expr>) -> Result<Expr, EvalError> {
-    self.inner.as_ref()(arguments)
+  // Wraps an ordinary Rust function, converting its arguments and result.
+  pub fn from_fn<F, Args>(name: &str, function: F) -> Native
+  where
+    F: IntoNative<Args>,
+  {
+    function.into_native(name)
+  }
+
+  pub fn name(&self) -> &Symbol {
+    &self.name
+  }
+
+  pub fn arity(&self) -> Arity {
+    self.arity
+  }
+
+  pub fn call(
+    &self,
+    evaluator: &mut Evaluator,
+    arguments: Vec<Expr>,
+  ) -> Result<Expr, EvalError> {
+    use EvalError::*;
+
+    if !self.arity.accepts(arguments.len()) {
+      return Err(WrongArity);
+    }
+
+    self.function.as_ref()(evaluator, arguments)
   }
 }
 
 impl PartialEq for Native {
   fn eq(&self, other: &Native) -> bool {
-    Rc::ptr_eq(&self.inner, &other.inner)
+    Rc::ptr_eq(&self.function, &other.function)
   }
 }
 
@@ -277,8 +543,13 @@ impl fmt::Display for Native {
 
 impl fmt::Debug for Native {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "Native")
+    write!(
+      f,
+      "Native {{ name: {:?}, arity: {:?} }}",
+      self.name, self.arity
+    )
   }
 }
 
-pub type NativeFn = fn(arguments: Vec<Expr>) -> Result<Expr, EvalError>;
+pub type NativeFn =
+  dyn Fn(&mut Evaluator, Vec<Expr>) -> Result<Expr, EvalError>;
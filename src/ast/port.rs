@@ -0,0 +1,344 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Cursor, Write};
+use std::mem;
+use std::rc::Rc;
+
+use super::{record_allocation, Expr};
+use crate::eval::EvalError;
+use crate::read::Reader;
+
+thread_local! {
+  // Ports over stdin share one buffer, so that none of them lose input
+  // buffered by another.
+  static STDIN: Port = Port::input(BufReader::new(io::stdin()));
+}
+
+// A stream that expressions and text are read from or written to.
+#[derive(Clone)]
+pub struct Port {
+  inner: Rc<RefCell<PortInner>>,
+}
+
+struct PortInner {
+  stream: Stream,
+  closed: bool,
+}
+
+enum Stream {
+  Input(Input),
+  Output(Output),
+}
+
+struct Input {
+  reader: Box<dyn BufRead>,
+  // Characters read from the reader but not yet consumed, like peeked ones.
+  pending: VecDeque<char>,
+}
+
+enum Output {
+  Writer(Box<dyn Write>),
+  String(String),
+}
+
+impl Port {
+  pub fn input<R>(reader: R) -> Port
+  where
+    R: BufRead + 'static,
+  {
+    Port::new(Stream::Input(Input {
+      reader: Box::new(reader),
+      pending: VecDeque::new(),
+    }))
+  }
+
+  pub fn output<W>(writer: W) -> Port
+  where
+    W: Write + 'static,
+  {
+    Port::new(Stream::Output(Output::Writer(Box::new(writer))))
+  }
+
+  // Creates an input port which reads from the string.
+  pub fn input_string<S>(string: S) -> Port
+  where
+    S: Into<String>,
+  {
+    Port::input(Cursor::new(string.into().into_bytes()))
+  }
+
+  // Creates an output port which collects what is written to it in a string.
+  pub fn output_string() -> Port {
+    Port::new(Stream::Output(Output::String(String::new())))
+  }
+
+  pub fn stdin() -> Port {
+    STDIN.with(Port::clone)
+  }
+
+  pub fn stdout() -> Port {
+    Port::output(io::stdout())
+  }
+
+  pub fn stderr() -> Port {
+    Port::output(io::stderr())
+  }
+
+  fn new(stream: Stream) -> Port {
+    record_allocation();
+
+    Port {
+      inner: Rc::new(RefCell::new(PortInner {
+        stream,
+        closed: false,
+      })),
+    }
+  }
+
+  pub fn is_input(&self) -> bool {
+    matches!(self.inner.borrow().stream, Stream::Input(_))
+  }
+
+  pub fn is_output(&self) -> bool {
+    matches!(self.inner.borrow().stream, Stream::Output(_))
+  }
+
+  pub fn is_closed(&self) -> bool {
+    self.inner.borrow().closed
+  }
+
+  // Returns None at the end of the input.
+  pub fn read_char(&self) -> Result<Option<char>, EvalError> {
+    self.with_input(|input| input.read_char())
+  }
+
+  pub fn peek_char(&self) -> Result<Option<char>, EvalError> {
+    self.with_input(|input| {
+      let char = input.read_char()?;
+      if let Some(char) = char {
+        input.pending.push_front(char);
+      }
+      Ok(char)
+    })
+  }
+
+  // Reads up to the end of the line, without the line ending.
+  pub fn read_line(&self) -> Result<Option<String>, EvalError> {
+    self.with_input(|input| {
+      let mut line = String::new();
+
+      loop {
+        match input.read_char()? {
+          Some('\n') => break,
+          Some(char) => line.push(char),
+          None if line.is_empty() => return Ok(None),
+          None => break,
+        }
+      }
+
+      if line.ends_with('\r') {
+        line.pop();
+      }
+
+      Ok(Some(line))
+    })
+  }
+
+  // Reads one expression, leaving whatever follows it to be read next.
+  pub fn read(&self) -> Result<Option<Expr>, EvalError> {
+    self.with_input(|input| {
+      let count = Cell::new(0);
+      let last = Cell::new(None);
+      let error = Cell::new(None);
+
+      let chars = std::iter::from_fn(|| match input.read_char() {
+        Ok(char) => {
+          count.set(count.get() + 1);
+          last.set(char);
+          char
+        }
+        Err(err) => {
+          error.set(Some(err));
+          None
+        }
+      });
+      let (result, offset) = {
+        let mut reader = Reader::new(chars.fuse());
+        (reader.read_datum(), reader.offset())
+      };
+
+      if let Some(error) = error.take() {
+        return Err(error);
+      }
+
+      // The reader looks one character past the end of some expressions,
+      // which is kept for the next read.
+      if count.get() > offset {
+        if let Some(char) = last.get() {
+          input.pending.push_front(char);
+        }
+      }
+
+      Ok(result?)
+    })
+  }
+
+  pub fn write_str(&self, string: &str) -> Result<(), EvalError> {
+    self.with_output(|output| {
+      match output {
+        Output::Writer(writer) => writer.write_all(string.as_bytes())?,
+        Output::String(buf) => buf.push_str(string),
+      }
+      Ok(())
+    })
+  }
+
+  pub fn flush(&self) -> Result<(), EvalError> {
+    self.with_output(|output| {
+      if let Output::Writer(writer) = output {
+        writer.flush()?;
+      }
+      Ok(())
+    })
+  }
+
+  // Returns what was written to an output string port.
+  pub fn output_string_contents(&self) -> Option<String> {
+    match &self.inner.borrow().stream {
+      Stream::Output(Output::String(string)) => Some(string.clone()),
+      _ => None,
+    }
+  }
+
+  // Closes the port, flushing it first. The files of file ports are closed
+  // right away, while the strings of string ports can still be read. The
+  // stdin port is shared by every evaluator on the thread, so it is never
+  // closed.
+  pub fn close(&self) -> Result<(), EvalError> {
+    if STDIN.with(|stdin| stdin == self) {
+      return Ok(());
+    }
+
+    let mut inner = self.inner.borrow_mut();
+    if inner.closed {
+      return Ok(());
+    }
+    inner.closed = true;
+
+    match &mut inner.stream {
+      Stream::Input(input) => {
+        input.pending.clear();
+        input.reader = Box::new(io::empty());
+      }
+      Stream::Output(Output::Writer(writer)) => {
+        let mut writer = mem::replace(writer, Box::new(io::sink()));
+        writer.flush()?;
+      }
+      Stream::Output(Output::String(_)) => {}
+    }
+
+    Ok(())
+  }
+
+  pub(crate) fn id(&self) -> usize {
+    Rc::as_ptr(&self.inner) as usize
+  }
+
+  fn with_input<T, F>(&self, f: F) -> Result<T, EvalError>
+  where
+    F: FnOnce(&mut Input) -> Result<T, EvalError>,
+  {
+    let mut inner = self.inner.borrow_mut();
+    if inner.closed {
+      return Err(EvalError::PortClosed);
+    }
+
+    match &mut inner.stream {
+      Stream::Input(input) => f(input),
+      Stream::Output(_) => Err(EvalError::InvalidType),
+    }
+  }
+
+  fn with_output<T, F>(&self, f: F) -> Result<T, EvalError>
+  where
+    F: FnOnce(&mut Output) -> Result<T, EvalError>,
+  {
+    let mut inner = self.inner.borrow_mut();
+    if inner.closed {
+      return Err(EvalError::PortClosed);
+    }
+
+    match &mut inner.stream {
+      Stream::Output(output) => f(output),
+      Stream::Input(_) => Err(EvalError::InvalidType),
+    }
+  }
+}
+
+impl Input {
+  // Decodes the next character from the reader as UTF-8.
+  fn read_char(&mut self) -> Result<Option<char>, EvalError> {
+    if let Some(char) = self.pending.pop_front() {
+      return Ok(Some(char));
+    }
+
+    let mut bytes = [0; 4];
+    if self.reader.read(&mut bytes[..1])? == 0 {
+      return Ok(None);
+    }
+
+    let width = match bytes[0] {
+      0x00..=0x7f => 1,
+      0xc0..=0xdf => 2,
+      0xe0..=0xef => 3,
+      0xf0..=0xf7 => 4,
+      _ => return Err(invalid_utf8()),
+    };
+    self.reader.read_exact(&mut bytes[1..width])?;
+
+    match std::str::from_utf8(&bytes[..width]) {
+      Ok(string) => Ok(string.chars().next()),
+      Err(_) => Err(invalid_utf8()),
+    }
+  }
+}
+
+fn invalid_utf8() -> EvalError {
+  let error = io::Error::new(
+    io::ErrorKind::InvalidData,
+    "stream did not contain valid UTF-8",
+  );
+  EvalError::Io(error)
+}
+
+impl Drop for PortInner {
+  // Output that hasn't been flushed is written when the port is dropped.
+  fn drop(&mut self) {
+    if let Stream::Output(Output::Writer(writer)) = &mut self.stream {
+      let _ = writer.flush();
+    }
+  }
+}
+
+impl PartialEq for Port {
+  fn eq(&self, other: &Port) -> bool {
+    Rc::ptr_eq(&self.inner, &other.inner)
+  }
+}
+
+impl fmt::Display for Port {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.is_input() {
+      write!(f, "Input Port")
+    } else {
+      write!(f, "Output Port")
+    }
+  }
+}
+
+impl fmt::Debug for Port {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Port({:x})", self.id())
+  }
+}
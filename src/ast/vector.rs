@@ -0,0 +1,100 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use super::{record_allocation, Expr};
+
+// A mutable, fixed-length array with constant-time indexing.
+#[derive(Clone)]
+pub struct Vector {
+  inner: Rc<RefCell<Vec<Expr>>>,
+}
+
+impl Vector {
+  pub fn new(items: Vec<Expr>) -> Vector {
+    record_allocation();
+
+    Vector {
+      inner: Rc::new(RefCell::new(items)),
+    }
+  }
+
+  pub fn get(&self, index: usize) -> Option<Expr> {
+    self.inner.borrow().get(index).cloned()
+  }
+
+  // Replaces the item at the index, returning false if it is out of bounds.
+  pub fn set(&self, index: usize, expr: Expr) -> bool {
+    match self.inner.borrow_mut().get_mut(index) {
+      Some(item) => {
+        *item = expr;
+        true
+      }
+      None => false,
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.inner.borrow().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn to_vec(&self) -> Vec<Expr> {
+    self.inner.borrow().clone()
+  }
+
+  pub(crate) fn id(&self) -> usize {
+    Rc::as_ptr(&self.inner) as usize
+  }
+
+  pub(crate) fn references(&self) -> usize {
+    Rc::strong_count(&self.inner)
+  }
+
+  // Removes every item, for breaking reference cycles.
+  pub(crate) fn take(&self) -> Option<Vec<Expr>> {
+    self
+      .inner
+      .try_borrow_mut()
+      .ok()
+      .map(|mut items| std::mem::take(&mut *items))
+  }
+
+  pub(crate) fn with_items<T, F>(&self, f: F) -> Option<T>
+  where
+    F: FnOnce(&[Expr]) -> T,
+  {
+    self.inner.try_borrow().ok().map(|items| f(&items))
+  }
+}
+
+impl PartialEq for Vector {
+  fn eq(&self, other: &Vector) -> bool {
+    Rc::ptr_eq(&self.inner, &other.inner)
+  }
+}
+
+impl fmt::Display for Vector {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "[{}]",
+      self
+        .inner
+        .borrow()
+        .iter()
+        .map(|expr| format!("{}", expr))
+        .collect::<Vec<String>>()
+        .join(" ")
+    )
+  }
+}
+
+impl fmt::Debug for Vector {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Vector {:?}", self.inner.borrow())
+  }
+}
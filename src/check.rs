@@ -0,0 +1,432 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::ast::{Arity, Atom, Expr, List, Location, Special, Symbol};
+use crate::env::Frame;
+use crate::eval::{EvalError, Evaluator};
+use crate::module::{self, Import};
+use crate::read;
+
+pub fn check(expr: &Expr) -> Vec<Warning> {
+  let evaluator = Evaluator::new();
+  evaluator.check(expr)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+  pub location: Option<Location>,
+  pub kind: WarningKind,
+}
+
+impl fmt::Display for Warning {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.location {
+      Some(location) => write!(f, "{}: {}", location, self.kind),
+      None => write!(f, "{}", self.kind),
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum WarningKind {
+  UndefinedSymbol(Symbol),
+  WrongArity { expected: Arity, found: usize },
+  InvalidForm(&'static str),
+  InvalidImport(String),
+  NotTopLevel(&'static str),
+}
+
+impl fmt::Display for WarningKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    use WarningKind::*;
+
+    match self {
+      UndefinedSymbol(symbol) => write!(f, "'{}' is undefined", symbol),
+      WrongArity { expected, found } => write!(
+        f,
+        "arity is wrong, expected {} arguments but found {}",
+        expected, found
+      ),
+      InvalidForm(message) => write!(f, "{}", message),
+      InvalidImport(message) => write!(f, "{}", message),
+      NotTopLevel(name) => write!(f, "'{}' must be at the top level", name),
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Binding {
+  Unknown,
+  Function(Arity),
+  Macro,
+}
+
+pub struct Checker {
+  frame: Frame,
+  path: Option<PathBuf>,
+  search_path: Vec<PathBuf>,
+  scopes: Vec<BTreeMap<Symbol, Binding>>,
+  top_level: bool,
+  warnings: Vec<Warning>,
+}
+
+impl Checker {
+  pub fn new(
+    frame: Frame,
+    path: Option<PathBuf>,
+    search_path: Vec<PathBuf>,
+  ) -> Checker {
+    Checker {
+      frame,
+      path,
+      search_path,
+      scopes: Vec::new(),
+      top_level: false,
+      warnings: Vec::new(),
+    }
+  }
+
+  pub fn check(mut self, expr: &Expr) -> Vec<Warning> {
+    let mut bindings = BTreeMap::new();
+    collect_bindings(expr, &mut bindings);
+
+    // Imported modules are only read for their exports, never evaluated.
+    for import in module::collect_imports(expr).unwrap_or_default() {
+      if let Ok(exports) = self.exports(&import) {
+        for (_, name) in import.bindings(&exports) {
+          bindings.insert(name, Binding::Unknown);
+        }
+      }
+    }
+
+    self.scopes.push(bindings);
+
+    self.top_level = true;
+    self.check_expr(expr, None);
+
+    self.warnings
+  }
+
+  fn check_expr(&mut self, expr: &Expr, location: Option<Location>) {
+    match expr {
+      Expr::List(List::Cons(_)) => self.check_list(expr, location),
+      Expr::Atom(Atom::Symbol(symbol)) if self.lookup(symbol).is_none() => {
+        self.warn(location, WarningKind::UndefinedSymbol(symbol.clone()));
+      }
+      // Items of vectors and maps aren't read with locations of their own.
+      Expr::Atom(Atom::Vector(vector)) => {
+        for expr in vector.to_vec().iter() {
+          self.check_expr(expr, location);
+        }
+      }
+      Expr::Atom(Atom::MapLiteral(literal)) => {
+        for (key, value) in literal.entries().iter() {
+          self.check_expr(key, location);
+          self.check_expr(value, location);
+        }
+      }
+      _ => {}
+    }
+  }
+
+  fn check_list(&mut self, expr: &Expr, location: Option<Location>) {
+    use WarningKind::*;
+
+    let mut nodes = nodes(expr).into_iter();
+    let (head, head_location) = nodes.next().unwrap();
+    let tail: Vec<(Expr, Option<Location>)> = nodes.collect();
+
+    let top_level = std::mem::replace(&mut self.top_level, false);
+
+    if let Expr::Atom(Atom::Special(special)) = &head {
+      self.check_special(special, &tail, location, top_level);
+      self.top_level = top_level;
+      return;
+    }
+
+    self.check_expr(&head, head_location);
+
+    let binding = match &head {
+      Expr::Atom(Atom::Symbol(symbol)) => self.lookup(symbol),
+      _ => None,
+    };
+
+    match binding {
+      // Macros take their arguments as raw terms.
+      Some(Binding::Macro) => return,
+      Some(Binding::Function(expected)) if !expected.accepts(tail.len()) => {
+        self.warn(
+          location,
+          WrongArity {
+            expected,
+            found: tail.len(),
+          },
+        );
+      }
+      _ => {}
+    }
+
+    for (expr, location) in tail.iter() {
+      self.check_expr(expr, *location);
+    }
+
+    self.top_level = top_level;
+  }
+
+  fn check_special(
+    &mut self,
+    special: &Special,
+    tail: &[(Expr, Option<Location>)],
+    location: Option<Location>,
+    top_level: bool,
+  ) {
+    use Special::*;
+    use WarningKind::*;
+
+    match special {
+      Begin => {
+        if tail.is_empty() {
+          self.warn(location, InvalidForm("'begin' expects an expression"));
+        }
+        for (expr, location) in tail.iter() {
+          self.top_level = top_level;
+          self.check_expr(expr, *location);
+        }
+      }
+      Define => {
+        if tail.len() != 2 {
+          self.warn(
+            location,
+            InvalidForm("'define' expects a symbol and a value"),
+          );
+        }
+        if let Some((name, name_location)) = tail.first() {
+          if !is_symbol(name) {
+            self.warn(
+              *name_location,
+              InvalidForm("'define' expects a symbol to define"),
+            );
+          }
+        }
+        self.check_all(tail.get(1..).unwrap_or(&[]));
+      }
+      Function | Macro => {
+        let name = if let Function = special {
+          "'function' expects a parameter list and a body"
+        } else {
+          "'macro' expects a parameter list and a body"
+        };
+
+        if tail.len() != 2 {
+          self.warn(location, InvalidForm(name));
+          return;
+        }
+
+        let (parameters, parameters_location) = &tail[0];
+        let (body, body_location) = &tail[1];
+
+        if let Expr::Atom(_) = parameters {
+          self.warn(*parameters_location, InvalidForm(name));
+          return;
+        }
+
+        let parameters = nodes(parameters);
+
+        if let Macro = special {
+          if parameters.len() != 1 {
+            self.warn(
+              *parameters_location,
+              InvalidForm("'macro' expects a single parameter"),
+            );
+          }
+        }
+
+        let mut bindings = BTreeMap::new();
+        for (parameter, location) in parameters.into_iter() {
+          match parameter {
+            Expr::Atom(Atom::Symbol(symbol)) => {
+              bindings.insert(symbol, Binding::Unknown);
+            }
+            _ => self.warn(location, InvalidForm("parameters must be symbols")),
+          }
+        }
+        collect_bindings(body, &mut bindings);
+
+        self.scopes.push(bindings);
+        self.check_expr(body, *body_location);
+        self.scopes.pop();
+      }
+      If => {
+        if tail.len() != 3 {
+          self.warn(
+            location,
+            InvalidForm("'if' expects a condition and two branches"),
+          );
+        }
+        self.check_all(tail);
+      }
+      Quote => {
+        if tail.len() != 1 {
+          self.warn(location, InvalidForm("'quote' expects one expression"));
+        }
+      }
+      Operator(_) => {
+        if tail.len() != 2 {
+          self.warn(location, InvalidForm("operators expect two operands"));
+        }
+        self.check_all(tail);
+      }
+      Import | Require => {
+        let name = if let Import = special {
+          "import"
+        } else {
+          "require"
+        };
+
+        if !top_level {
+          self.warn(location, NotTopLevel(name));
+        }
+
+        let terms = tail
+          .iter()
+          .rev()
+          .fold(List::Nil, |list, (expr, _)| List::cons(expr.clone(), list));
+
+        let result = module::Import::parse(special, &terms)
+          .and_then(|import| self.exports(&import));
+
+        if let Err(error) = result {
+          self.warn(location, InvalidImport(error.to_string()));
+        }
+      }
+      Export => {
+        if !top_level {
+          self.warn(location, NotTopLevel("export"));
+        }
+
+        for (expr, location) in tail.iter() {
+          if is_symbol(expr) {
+            self.check_expr(expr, *location);
+          } else {
+            self.warn(*location, InvalidForm("'export' expects symbols"));
+          }
+        }
+      }
+    }
+  }
+
+  fn exports(&self, import: &Import) -> Result<Vec<Symbol>, EvalError> {
+    let path = import.find(self.path.as_deref(), &self.search_path)?;
+    let source = fs::read_to_string(path)?;
+    let expr = read::read(&source)?;
+
+    module::collect_exports(&expr)
+  }
+
+  fn check_all(&mut self, exprs: &[(Expr, Option<Location>)]) {
+    for (expr, location) in exprs.iter() {
+      self.check_expr(expr, *location);
+    }
+  }
+
+  fn lookup(&self, symbol: &Symbol) -> Option<Binding> {
+    for scope in self.scopes.iter().rev() {
+      if let Some(binding) = scope.get(symbol) {
+        return Some(binding.clone());
+      }
+    }
+
+    let binding = match self.frame.get(symbol)? {
+      Expr::Atom(Atom::Function(function)) => {
+        Binding::Function(Arity::Exact(function.parameters().len()))
+      }
+      Expr::Atom(Atom::Native(native)) => Binding::Function(native.arity()),
+      Expr::Atom(Atom::Macro(_)) => Binding::Macro,
+      _ => Binding::Unknown,
+    };
+
+    Some(binding)
+  }
+
+  fn warn(&mut self, location: Option<Location>, kind: WarningKind) {
+    self.warnings.push(Warning { location, kind });
+  }
+}
+
+// Finds the symbols defined directly in a body, along with what is known
+// about their values.
+fn collect_bindings(expr: &Expr, bindings: &mut BTreeMap<Symbol, Binding>) {
+  use Special::*;
+
+  let list = match expr {
+    Expr::List(list @ List::Cons(_)) => list,
+    _ => return,
+  };
+
+  let nodes = nodes(expr);
+
+  match &nodes[0].0 {
+    Expr::Atom(Atom::Special(Function))
+    | Expr::Atom(Atom::Special(Macro))
+    | Expr::Atom(Atom::Special(Quote)) => return,
+    Expr::Atom(Atom::Special(Define)) => {
+      if let Some((Expr::Atom(Atom::Symbol(symbol)), _)) = nodes.get(1) {
+        let binding = nodes
+          .get(2)
+          .map(|(value, _)| classify(value))
+          .unwrap_or(Binding::Unknown);
+
+        let binding = match bindings.get(symbol) {
+          Some(existing) if *existing != binding => Binding::Unknown,
+          _ => binding,
+        };
+
+        bindings.insert(symbol.clone(), binding);
+      }
+    }
+    _ => {}
+  }
+
+  for expr in list.clone().into_iter() {
+    collect_bindings(&expr, bindings);
+  }
+}
+
+fn classify(expr: &Expr) -> Binding {
+  use Special::*;
+
+  let list = match expr {
+    Expr::List(list) => list,
+    _ => return Binding::Unknown,
+  };
+
+  match (list.get(0), list.get(1)) {
+    (
+      Some(Expr::Atom(Atom::Special(Function))),
+      Some(Expr::List(parameters)),
+    ) => Binding::Function(Arity::Exact(parameters.len())),
+    (Some(Expr::Atom(Atom::Special(Macro))), _) => Binding::Macro,
+    _ => Binding::Unknown,
+  }
+}
+
+fn nodes(expr: &Expr) -> Vec<(Expr, Option<Location>)> {
+  let mut list = match expr {
+    Expr::List(list) => list.clone(),
+    _ => return Vec::new(),
+  };
+
+  let mut nodes = Vec::new();
+  while let List::Cons(node) = list {
+    nodes.push((node.head.clone(), node.location));
+    list = node.tail.clone();
+  }
+
+  nodes
+}
+
+fn is_symbol(expr: &Expr) -> bool {
+  matches!(expr, Expr::Atom(Atom::Symbol(_)))
+}
@@ -0,0 +1,158 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::eval::EvalError;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capability {
+  Console,
+  FsRead,
+  FsWrite,
+  Process,
+  Env,
+  Net,
+}
+
+impl fmt::Display for Capability {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    use Capability::*;
+
+    match self {
+      Console => write!(f, "console"),
+      FsRead => write!(f, "filesystem read"),
+      FsWrite => write!(f, "filesystem write"),
+      Process => write!(f, "process"),
+      Env => write!(f, "environment"),
+      Net => write!(f, "network"),
+    }
+  }
+}
+
+// What the natives of an evaluator are allowed to do. Natives are always
+// defined, but fail with `PermissionDenied` when they need a capability that
+// wasn't granted.
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+  pub console: bool,
+  pub fs: FsAccess,
+  pub process: bool,
+  pub env: bool,
+  pub net: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FsAccess {
+  pub read: bool,
+  pub write: bool,
+  // Directory outside of which no files can be accessed.
+  pub root: Option<PathBuf>,
+}
+
+impl FsAccess {
+  pub fn read_only() -> FsAccess {
+    FsAccess {
+      read: true,
+      write: false,
+      root: None,
+    }
+  }
+
+  pub fn read_write() -> FsAccess {
+    FsAccess {
+      read: true,
+      write: true,
+      root: None,
+    }
+  }
+
+  pub fn scoped<P>(mut self, root: P) -> FsAccess
+  where
+    P: Into<PathBuf>,
+  {
+    self.root = Some(root.into());
+    self
+  }
+}
+
+impl Capabilities {
+  pub fn all() -> Capabilities {
+    Capabilities {
+      console: true,
+      fs: FsAccess::read_write(),
+      process: true,
+      env: true,
+      net: true,
+    }
+  }
+
+  pub fn none() -> Capabilities {
+    Capabilities {
+      console: false,
+      fs: FsAccess::default(),
+      process: false,
+      env: false,
+      net: false,
+    }
+  }
+
+  pub fn check(&self, capability: Capability) -> Result<(), EvalError> {
+    use Capability::*;
+
+    let granted = match capability {
+      Console => self.console,
+      FsRead => self.fs.read,
+      FsWrite => self.fs.write,
+      Process => self.process,
+      Env => self.env,
+      Net => self.net,
+    };
+
+    if granted {
+      Ok(())
+    } else {
+      Err(EvalError::PermissionDenied(capability))
+    }
+  }
+
+  // Checks that the file at the path can be accessed, returning the path it
+  // resolves to.
+  pub fn check_path(
+    &self,
+    path: &Path,
+    capability: Capability,
+  ) -> Result<PathBuf, EvalError> {
+    self.check(capability)?;
+
+    let root = match &self.fs.root {
+      Some(root) => root.canonicalize()?,
+      None => return Ok(path.to_path_buf()),
+    };
+
+    // Files that don't exist yet are resolved through their directory.
+    let resolved = match path.canonicalize() {
+      Ok(path) => path,
+      Err(_) => {
+        let parent = match path.parent() {
+          Some(parent) if parent != Path::new("") => parent.canonicalize()?,
+          _ => Path::new(".").canonicalize()?,
+        };
+        match path.file_name() {
+          Some(name) => parent.join(name),
+          None => parent,
+        }
+      }
+    };
+
+    if resolved.starts_with(&root) {
+      Ok(resolved)
+    } else {
+      Err(EvalError::PermissionDenied(capability))
+    }
+  }
+}
+
+impl Default for Capabilities {
+  fn default() -> Capabilities {
+    Capabilities::all()
+  }
+}
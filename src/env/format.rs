@@ -0,0 +1,117 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::ast::{Atom, Expr};
+use crate::eval::EvalError;
+
+// Widths and precisions are limited, as the padding and digits are allocated
+// all at once, out of reach of an evaluator's budget.
+const MAX_WIDTH: usize = 1000;
+
+// Formats the arguments into a template, using the directives:
+//
+// * `~a` writes the next argument in its human form, like `display`.
+// * `~s` writes the next argument in its readable form, like `write`.
+// * `~d` writes the next argument, which must be a number.
+// * `~f` writes the next argument, which must be a number, with a precision
+//   given like `~.2f`.
+// * `~%` writes a newline and `~~` writes a tilde.
+//
+// A width can be given before the directive, like `~8a`. Numbers are padded on
+// the left and everything else on the right.
+pub fn format(
+  template: &str,
+  arguments: Vec<Expr>,
+) -> Result<String, EvalError> {
+  let mut output = String::new();
+  let mut arguments = arguments.into_iter();
+  let mut chars = template.chars().peekable();
+
+  while let Some(char) = chars.next() {
+    if char != '~' {
+      output.push(char);
+      continue;
+    }
+
+    let width = read_digits(&mut chars)?;
+    let precision = match chars.peek() {
+      Some('.') => {
+        chars.next();
+        Some(read_digits(&mut chars)?.unwrap_or(0))
+      }
+      _ => None,
+    };
+
+    let directive = match chars.next() {
+      Some(directive) => directive,
+      None => return Err(EvalError::InvalidFormat("~".to_string())),
+    };
+    let mut next_argument = || arguments.next().ok_or(EvalError::WrongArity);
+
+    let (text, is_number) = match directive {
+      'a' => (next_argument()?.to_display_string(), false),
+      's' => (next_argument()?.to_string(), false),
+      'd' => (as_number(next_argument()?)?.to_string(), true),
+      'f' => {
+        let number = as_number(next_argument()?)?;
+        match precision {
+          Some(precision) => (format!("{:.*}", precision, number), true),
+          None => (number.to_string(), true),
+        }
+      }
+      '%' => ("\n".to_string(), false),
+      '~' => ("~".to_string(), false),
+      directive => {
+        return Err(EvalError::InvalidFormat(format!("~{}", directive)))
+      }
+    };
+
+    let padding = width.unwrap_or(0).saturating_sub(text.chars().count());
+    let padding = " ".repeat(padding);
+
+    if is_number {
+      output.push_str(&padding);
+      output.push_str(&text);
+    } else {
+      output.push_str(&text);
+      output.push_str(&padding);
+    }
+  }
+
+  // Every argument should be used by a directive.
+  if arguments.next().is_some() {
+    return Err(EvalError::WrongArity);
+  }
+
+  Ok(output)
+}
+
+fn read_digits(
+  chars: &mut Peekable<Chars>,
+) -> Result<Option<usize>, EvalError> {
+  let mut digits = String::new();
+
+  while let Some(char) = chars.peek() {
+    if !char.is_ascii_digit() {
+      break;
+    }
+    digits.push(*char);
+    chars.next();
+  }
+
+  if digits.is_empty() {
+    return Ok(None);
+  }
+
+  match digits.parse() {
+    Ok(number) if number <= MAX_WIDTH => Ok(Some(number)),
+    _ => Err(EvalError::InvalidFormat(format!("~{}", digits))),
+  }
+}
+
+fn as_number(expr: Expr) -> Result<f64, EvalError> {
+  match expr {
+    Expr::Atom(Atom::Number(number)) => Ok(number),
+    _ => Err(EvalError::InvalidType),
+  }
+}
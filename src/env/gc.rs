@@ -0,0 +1,266 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::{Rc, Weak};
+
+use crate::ast::{Atom, Expr, List, MapNode, Vector};
+use crate::env::{Frame, FrameInner};
+
+// Frames form reference cycles with the closures defined in them, which
+// reference counting alone never frees. The collector finds frames that are
+// only referenced by such cycles using trial deletion. References it can't
+// see, like those held by Rust code, only make objects look reachable, so it
+// never frees anything that is still in use.
+
+thread_local! {
+  static FRAMES: RefCell<Registry> = const {
+    RefCell::new(Registry {
+      frames: Vec::new(),
+      threshold: 256,
+    })
+  };
+}
+
+struct Registry {
+  frames: Vec<Weak<RefCell<FrameInner>>>,
+  // Length of the registry at which dropped frames are pruned.
+  threshold: usize,
+}
+
+pub(crate) fn register(inner: &Rc<RefCell<FrameInner>>) {
+  FRAMES.with(|registry| {
+    let mut registry = registry.borrow_mut();
+
+    if registry.frames.len() >= registry.threshold {
+      registry.frames.retain(|frame| frame.strong_count() > 0);
+      registry.threshold = (registry.frames.len() * 2).max(256);
+    }
+
+    registry.frames.push(Rc::downgrade(inner));
+  });
+}
+
+// Returns the number of frames alive on this thread.
+pub fn live_frames() -> usize {
+  FRAMES.with(|registry| {
+    let mut registry = registry.borrow_mut();
+    registry.frames.retain(|frame| frame.strong_count() > 0);
+    registry.frames.len()
+  })
+}
+
+struct Object {
+  // Strong references to the object.
+  references: usize,
+  // Strong references to the object from other objects in the graph.
+  internal: usize,
+  edges: Vec<usize>,
+}
+
+#[derive(Default)]
+struct Graph {
+  objects: HashMap<usize, Object>,
+  // Vectors can also form cycles, so they are kept to be cleared too.
+  vectors: Vec<(usize, Vector)>,
+}
+
+// Frees the frames and vectors on this thread that are only referenced by
+// cycles, returning how many frames were freed.
+pub fn collect() -> usize {
+  let frames: Vec<Frame> = FRAMES.with(|registry| {
+    let mut registry = registry.borrow_mut();
+    registry.frames.retain(|frame| frame.strong_count() > 0);
+    registry
+      .frames
+      .iter()
+      .filter_map(Weak::upgrade)
+      .map(|inner| Frame { inner })
+      .collect()
+  });
+
+  let mut graph = Graph::default();
+
+  // The frames collected above each hold one extra reference.
+  for frame in frames.iter() {
+    graph.add_object(frame.id(), Rc::strong_count(&frame.inner) - 1);
+  }
+
+  for frame in frames.iter() {
+    // Frames in use can't be traced, so they are left alone.
+    let inner = match frame.inner.try_borrow() {
+      Ok(inner) => inner,
+      Err(_) => continue,
+    };
+
+    if let Some(parent) = &inner.parent {
+      graph.add_edge(frame.id(), parent.id());
+    }
+    for expr in inner.slots.iter().flatten() {
+      graph.trace_expr(frame.id(), expr);
+    }
+  }
+
+  let alive = graph.alive();
+
+  let mut garbage = Vec::new();
+  for frame in frames.iter() {
+    if alive.contains(&frame.id()) {
+      continue;
+    }
+    if let Ok(mut inner) = frame.inner.try_borrow_mut() {
+      garbage.push((inner.parent.take(), mem::take(&mut inner.slots)));
+    }
+  }
+
+  let mut vector_garbage = Vec::new();
+  for (id, vector) in graph.vectors.iter() {
+    if !alive.contains(id) {
+      vector_garbage.push(vector.take());
+    }
+  }
+
+  // Dropping the contents of the frames breaks their cycles.
+  let count = garbage.len();
+  drop(graph);
+  drop(vector_garbage);
+  drop(garbage);
+
+  count
+}
+
+impl Graph {
+  // Objects with references from outside the graph are roots, and anything
+  // they reach is alive.
+  fn alive(&self) -> HashSet<usize> {
+    let mut stack: Vec<usize> = self
+      .objects
+      .iter()
+      .filter(|(_, object)| object.references > object.internal)
+      .map(|(id, _)| *id)
+      .collect();
+    let mut alive: HashSet<usize> = stack.iter().cloned().collect();
+
+    while let Some(id) = stack.pop() {
+      for edge in self.objects[&id].edges.iter() {
+        if alive.insert(*edge) {
+          stack.push(*edge);
+        }
+      }
+    }
+
+    alive
+  }
+
+  // Adds an object to the graph, returning whether it was new.
+  fn add_object(&mut self, id: usize, references: usize) -> bool {
+    if self.objects.contains_key(&id) {
+      return false;
+    }
+
+    self.objects.insert(
+      id,
+      Object {
+        references,
+        internal: 0,
+        edges: Vec::new(),
+      },
+    );
+
+    true
+  }
+
+  fn add_edge(&mut self, from: usize, to: usize) {
+    if !self.objects.contains_key(&to) {
+      return;
+    }
+
+    self.objects.get_mut(&to).unwrap().internal += 1;
+    self.objects.get_mut(&from).unwrap().edges.push(to);
+  }
+
+  fn trace_expr(&mut self, from: usize, expr: &Expr) {
+    match expr {
+      Expr::Atom(Atom::Function(function)) => {
+        let id = function.id();
+        let new = self.add_object(id, function.references());
+        self.add_edge(from, id);
+        if new {
+          self.add_edge(id, function.frame().id());
+        }
+      }
+      Expr::Atom(Atom::Macro(macr)) => {
+        let id = macr.id();
+        let new = self.add_object(id, macr.references());
+        self.add_edge(from, id);
+        if new {
+          self.add_edge(id, macr.frame().id());
+        }
+      }
+      Expr::Atom(Atom::Vector(vector)) => {
+        let id = vector.id();
+        let new = self.add_object(id, vector.references());
+        self.add_edge(from, id);
+        if new {
+          // Vectors being changed can't be traced, like frames in use.
+          vector.with_items(|items| {
+            for item in items.iter() {
+              self.trace_expr(id, item);
+            }
+          });
+          self.vectors.push((id, vector.clone()));
+        }
+      }
+      Expr::Atom(Atom::Map(map)) => {
+        if let Some(root) = map.root() {
+          self.trace_map(from, root);
+        }
+      }
+      Expr::Atom(Atom::Environment(frame)) => self.add_edge(from, frame.id()),
+      Expr::List(list) => self.trace_list(from, list),
+      _ => {}
+    }
+  }
+
+  // Maps share nodes with each other, so each node is an object of its own.
+  fn trace_map(&mut self, from: usize, node: &Rc<MapNode>) {
+    let id = Rc::as_ptr(node) as usize;
+    let new = self.add_object(id, Rc::strong_count(node));
+    self.add_edge(from, id);
+    if !new {
+      return;
+    }
+
+    match node.as_ref() {
+      MapNode::Branch { children, .. } => {
+        for child in children.iter() {
+          self.trace_map(id, child);
+        }
+      }
+      MapNode::Leaf { entries, .. } => {
+        for (_, value) in entries.iter() {
+          self.trace_expr(id, value);
+        }
+      }
+    }
+  }
+
+  // Lists can be long, so they are traced iteratively along their tails.
+  fn trace_list(&mut self, from: usize, list: &List) {
+    let mut from = from;
+    let mut list = list;
+
+    while let List::Cons(node) = list {
+      let id = Rc::as_ptr(node) as usize;
+      let new = self.add_object(id, Rc::strong_count(node));
+      self.add_edge(from, id);
+      if !new {
+        break;
+      }
+
+      self.trace_expr(id, &node.head);
+
+      from = id;
+      list = &node.tail;
+    }
+  }
+}
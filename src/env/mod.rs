@@ -2,31 +2,121 @@ use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::rc::Rc;
 
-use crate::ast::{Expr, Symbol};
+use crate::ast::{record_allocation, Expr, Symbol};
 
+pub use self::capability::{Capabilities, Capability, FsAccess};
+pub use self::format::format;
+pub use self::gc::{collect, live_frames};
 pub use self::prelude::build_base_frame;
 
+mod capability;
+mod format;
+mod gc;
 mod prelude;
 
+// Maps the symbols bound in a frame to slot indices, shared by every frame
+// created from the same function.
+#[derive(Clone, Debug)]
+pub struct Scope {
+  inner: Rc<RefCell<ScopeInner>>,
+}
+
+#[derive(Debug)]
+struct ScopeInner {
+  parent: Option<Scope>,
+  global: bool,
+  indices: BTreeMap<Symbol, usize>,
+}
+
+impl Scope {
+  pub fn new() -> Scope {
+    Scope::create(None, true)
+  }
+
+  pub fn with_parent(parent: Scope) -> Scope {
+    Scope::create(Some(parent), false)
+  }
+
+  // Creates the top-level scope of a module, which sits on top of the base.
+  pub fn global_with_parent(parent: Scope) -> Scope {
+    Scope::create(Some(parent), true)
+  }
+
+  fn create(parent: Option<Scope>, global: bool) -> Scope {
+    Scope {
+      inner: Rc::new(RefCell::new(ScopeInner {
+        parent,
+        global,
+        indices: BTreeMap::new(),
+      })),
+    }
+  }
+
+  pub fn parent(&self) -> Option<Scope> {
+    self.inner.borrow().parent.clone()
+  }
+
+  pub fn is_global(&self) -> bool {
+    self.inner.borrow().global
+  }
+
+  pub fn len(&self) -> usize {
+    self.inner.borrow().indices.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn index(&self, symbol: &Symbol) -> Option<usize> {
+    self.inner.borrow().indices.get(symbol).cloned()
+  }
+
+  pub fn define(&self, symbol: Symbol) -> usize {
+    let mut inner = self.inner.borrow_mut();
+    let index = inner.indices.len();
+    *inner.indices.entry(symbol).or_insert(index)
+  }
+
+  pub fn resolve(&self, symbol: &Symbol) -> Option<(usize, usize)> {
+    if let Some(index) = self.index(symbol) {
+      return Some((0, index));
+    }
+
+    self
+      .parent()
+      .and_then(|parent| parent.resolve(symbol))
+      .map(|(depth, index)| (depth + 1, index))
+  }
+}
+
+impl Default for Scope {
+  fn default() -> Scope {
+    Scope::new()
+  }
+}
+
 #[derive(Clone, Debug)]
 pub struct Frame {
   inner: Rc<RefCell<FrameInner>>,
 }
 
+impl PartialEq for Frame {
+  fn eq(&self, other: &Frame) -> bool {
+    Rc::ptr_eq(&self.inner, &other.inner)
+  }
+}
+
 #[derive(Debug)]
 struct FrameInner {
   parent: Option<Frame>,
-  variables: BTreeMap<Symbol, Expr>,
+  scope: Scope,
+  slots: Vec<Option<Expr>>,
 }
 
 impl Frame {
   pub fn new() -> Frame {
-    Frame {
-      inner: Rc::new(RefCell::new(FrameInner {
-        parent: None,
-        variables: BTreeMap::new(),
-      })),
-    }
+    Frame::create(None, Scope::new(), Vec::new())
   }
 
   pub fn base() -> Frame {
@@ -34,28 +124,100 @@ impl Frame {
   }
 
   pub fn with_parent(parent: Frame) -> Frame {
-    Frame {
-      inner: Rc::new(RefCell::new(FrameInner {
-        parent: Some(parent),
-        variables: BTreeMap::new(),
-      })),
-    }
+    let scope = Scope::global_with_parent(parent.scope());
+    Frame::with_scope(parent, scope)
+  }
+
+  pub fn with_scope(parent: Frame, scope: Scope) -> Frame {
+    let slots = vec![None; scope.len()];
+    Frame::create(Some(parent), scope, slots)
+  }
+
+  fn create(
+    parent: Option<Frame>,
+    scope: Scope,
+    slots: Vec<Option<Expr>>,
+  ) -> Frame {
+    record_allocation();
+
+    let inner = Rc::new(RefCell::new(FrameInner {
+      parent,
+      scope,
+      slots,
+    }));
+    gc::register(&inner);
+
+    Frame { inner }
+  }
+
+  pub(crate) fn id(&self) -> usize {
+    Rc::as_ptr(&self.inner) as usize
+  }
+
+  pub fn scope(&self) -> Scope {
+    self.inner.borrow().scope.clone()
   }
 
+  // Slots are allocated for every `define` in a scope, even those that never
+  // run, so an empty slot falls back to the binding further out.
   pub fn get(&self, symbol: &Symbol) -> Option<Expr> {
-    if let Some(expr) = self.inner.borrow().variables.get(symbol) {
-      Some(expr.clone())
+    let inner = self.inner.borrow();
+
+    let expr = inner
+      .scope
+      .index(symbol)
+      .and_then(|index| inner.slots.get(index).cloned().flatten());
+
+    match expr {
+      Some(expr) => Some(expr),
+      None => inner.parent.as_ref().and_then(|parent| parent.get(symbol)),
+    }
+  }
+
+  pub fn set(&mut self, symbol: Symbol, expr: Expr) {
+    let index = self.inner.borrow().scope.define(symbol);
+    self.set_at(index, expr);
+  }
+
+  // Like `get`, an empty slot falls back to the binding of the symbol further
+  // out.
+  pub fn get_at(
+    &self,
+    depth: usize,
+    index: usize,
+    symbol: &Symbol,
+  ) -> Option<Expr> {
+    let inner = self.inner.borrow();
+
+    if depth == 0 {
+      inner
+        .slots
+        .get(index)
+        .cloned()
+        .flatten()
+        .or_else(|| inner.parent.as_ref().and_then(|parent| parent.get(symbol)))
     } else {
-      self
-        .inner
-        .borrow()
+      inner
         .parent
         .as_ref()
-        .and_then(|parent| parent.get(symbol))
+        .and_then(|parent| parent.get_at(depth - 1, index, symbol))
     }
   }
 
-  pub fn set(&mut self, symbol: Symbol, expr: Expr) {
-    self.inner.borrow_mut().variables.insert(symbol, expr);
+  pub fn set_at(&mut self, index: usize, expr: Expr) {
+    let mut inner = self.inner.borrow_mut();
+
+    // The scope may have grown since this frame was created.
+    if index >= inner.slots.len() {
+      inner.slots.resize(index + 1, None);
+    }
+
+    inner.slots[index] = Some(expr);
+  }
+}
+
+impl Default for Frame {
+  fn default() -> Frame {
+    Frame::new()
   }
 }
@@ -1,218 +1,944 @@
-use crate::ast::{self, Atom, Expr, List, SYMBOL_TRUE};
-use crate::env::Frame;
-use crate::eval::EvalError;
-
+use std::cell::Cell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::ast::{
+  Arity, Atom, Expr, FromExpr, IntoExpr, IntoNative, Key, Keyword, List, Map,
+  Native, Port, Symbol, Vector,
+};
+use crate::env::{self, Capability, Frame};
+use crate::eval::{EvalError, Evaluator};
+use crate::read::{self, Reader};
+
+// Natives are grouped by the capabilities they need. Every group is always
+// defined, and natives check their capabilities when called.
 pub fn build_base_frame() -> Frame {
-  use ast::Atom::*;
-  use ast::Native;
-  use ast::Symbol;
-  use Expr::*;
-
   let mut frame = Frame::new();
 
-  frame.set(SYMBOL_TRUE.clone(), Atom(Symbol(SYMBOL_TRUE.clone())));
+  add_core(&mut frame);
+  add_console(&mut frame);
+  add_fs(&mut frame);
+  add_env(&mut frame);
+  add_process(&mut frame);
 
-  frame.set(Symbol::new("print"), Atom(Native(Native::new(print))));
-  frame.set(Symbol::new("head"), Atom(Native(Native::new(head))));
-  frame.set(Symbol::new("tail"), Atom(Native(Native::new(tail))));
-  frame.set(Symbol::new("cons"), Atom(Native(Native::new(cons))));
+  frame
+}
 
-  frame.set(Symbol::new("number?"), Atom(Native(Native::new(is_number))));
-  frame.set(Symbol::new("string?"), Atom(Native(Native::new(is_string))));
-  frame.set(Symbol::new("symbol?"), Atom(Native(Native::new(is_symbol))));
-  frame.set(
-    Symbol::new("function?"),
-    Atom(Native(Native::new(is_function))),
+fn add_core(frame: &mut Frame) {
+  use Arity::*;
+
+  set_function(frame, "head", head);
+  set_function(frame, "tail", tail);
+  set_function(frame, "cons", cons);
+  set_native(frame, "sort", Exact(2), sort);
+
+  set_function(frame, "boolean?", is_boolean);
+  set_function(frame, "number?", is_number);
+  set_function(frame, "string?", is_string);
+  set_function(frame, "symbol?", is_symbol);
+  set_function(frame, "keyword?", is_keyword);
+  set_function(frame, "char?", is_char);
+  set_function(frame, "function?", is_function);
+  set_function(frame, "special?", is_special);
+  set_function(frame, "native?", is_native);
+  set_function(frame, "environment?", is_environment);
+
+  set_function(frame, "sqrt", f64::sqrt);
+
+  set_function(frame, "keyword->string", |keyword: Keyword| {
+    keyword.as_str().to_string()
+  });
+  set_function(frame, "string->keyword", |name: String| Keyword::new(name));
+
+  set_function(frame, "char->integer", |char: char| char as u32);
+  set_function(frame, "integer->char", integer_to_char);
+  set_function(frame, "char-upcase", char_upcase);
+  set_function(frame, "char-downcase", char_downcase);
+  set_function(frame, "char-alphabetic?", char::is_alphabetic);
+  set_function(frame, "char-numeric?", char::is_numeric);
+  set_function(frame, "char-whitespace?", char::is_whitespace);
+  set_function(frame, "char-upper-case?", char::is_uppercase);
+  set_function(frame, "char-lower-case?", char::is_lowercase);
+
+  // Strings are indexed by character rather than by byte.
+  set_function(frame, "string-length", |string: String| {
+    string.chars().count()
+  });
+  set_function(frame, "string-ref", string_ref);
+  set_native(frame, "string-append", AtLeast(0), string_append);
+  set_native(frame, "substring", Range(2, 3), substring);
+  set_function(frame, "string-split", string_split);
+  set_function(
+    frame,
+    "string-join",
+    |strings: Vec<String>, separator: String| strings.join(&separator),
+  );
+  set_function(frame, "string-trim", |string: String| {
+    string.trim().to_string()
+  });
+  set_function(frame, "string-upcase", |string: String| {
+    string.to_uppercase()
+  });
+  set_function(frame, "string-downcase", |string: String| {
+    string.to_lowercase()
+  });
+  set_function(frame, "string-contains?", |string: String, part: String| {
+    string.contains(&part)
+  });
+  set_function(
+    frame,
+    "string-replace",
+    |string: String, from: String, to: String| string.replace(&from, &to),
   );
-  frame.set(
-    Symbol::new("special?"),
-    Atom(Native(Native::new(is_special))),
+  set_function(frame, "string->number", string_to_number);
+  set_function(frame, "number->string", |number: f64| number.to_string());
+  set_function(frame, "symbol->string", |symbol: Symbol| {
+    symbol.as_str().to_string()
+  });
+  set_function(frame, "string->symbol", |name: String| Symbol::new(name));
+  set_function(frame, "string->list", |string: String| {
+    string.chars().collect::<Vec<char>>()
+  });
+  set_function(frame, "list->string", |chars: Vec<char>| {
+    chars.into_iter().collect::<String>()
+  });
+
+  set_native(frame, "vector", AtLeast(0), |_, items| {
+    Ok(Expr::Atom(Atom::Vector(Vector::new(items))))
+  });
+  set_function(frame, "vector?", is_vector);
+  set_function(frame, "vector-ref", vector_ref);
+  set_function(frame, "vector-set!", vector_set);
+  set_function(frame, "vector-length", |vector: Vector| vector.len());
+  set_function(frame, "vector->list", |vector: Vector| vector.to_vec());
+  set_function(frame, "list->vector", |list: List| {
+    Vector::new(list.into_iter().collect())
+  });
+
+  set_native(frame, "hash-map", AtLeast(0), hash_map);
+  set_function(frame, "map?", is_map);
+  set_native(frame, "map-get", Range(2, 3), map_get);
+  set_function(frame, "map-put", |map: Map, key: Key, value: Expr| {
+    map.insert(key, value)
+  });
+  set_function(frame, "map-remove", |map: Map, key: Key| map.remove(&key));
+  set_function(frame, "map-contains?", |map: Map, key: Key| {
+    map.contains(&key)
+  });
+  set_function(frame, "map-keys", |map: Map| {
+    map
+      .entries()
+      .into_iter()
+      .map(|(key, _)| key)
+      .collect::<Vec<Key>>()
+  });
+  set_function(frame, "map-values", |map: Map| {
+    map
+      .entries()
+      .into_iter()
+      .map(|(_, value)| value)
+      .collect::<Vec<Expr>>()
+  });
+  set_function(frame, "map-size", |map: Map| map.len());
+  set_function(frame, "map-merge", map_merge);
+
+  // Each generated symbol is numbered by a counter owned by the native.
+  let counter = Cell::new(0);
+  set_function(frame, "gensym", move || {
+    counter.set(counter.get() + 1);
+    Symbol::new(format!("g{}", counter.get()))
+  });
+
+  set_function(frame, "port?", |expr: Expr| {
+    matches!(expr, Expr::Atom(Atom::Port(_)))
+  });
+  set_function(frame, "input-port?", |expr: Expr| match expr {
+    Expr::Atom(Atom::Port(port)) => port.is_input(),
+    _ => false,
+  });
+  set_function(frame, "output-port?", |expr: Expr| match expr {
+    Expr::Atom(Atom::Port(port)) => port.is_output(),
+    _ => false,
+  });
+  set_function(frame, "eof-object", || Expr::Atom(Atom::Eof));
+  set_function(frame, "eof-object?", |expr: Expr| {
+    matches!(expr, Expr::Atom(Atom::Eof))
+  });
+  set_function(frame, "open-input-string", Port::input_string::<String>);
+  set_function(frame, "open-output-string", Port::output_string);
+  set_function(frame, "get-output-string", |port: Port| {
+    port.output_string_contents().ok_or(EvalError::InvalidType)
+  });
+  // Reading past the end of a port returns the end of file object.
+  set_input_native(frame, "read-line", |port| Ok(or_eof(port.read_line()?)));
+  set_input_native(frame, "read-char", |port| Ok(or_eof(port.read_char()?)));
+  set_input_native(frame, "peek-char", |port| Ok(or_eof(port.peek_char()?)));
+  set_input_native(frame, "read", |port| Ok(or_eof(port.read()?)));
+  set_function(frame, "flush", |port: Port| port.flush());
+  set_function(frame, "close-port", |port: Port| port.close());
+  set_native(
+    frame,
+    "with-output-to-string",
+    Exact(1),
+    with_output_to_string,
   );
-  frame.set(Symbol::new("native?"), Atom(Native(Native::new(is_native))));
 
-  frame.set(Symbol::new("sqrt"), Atom(Native(Native::new(sqrt))));
+  set_native(frame, "try", Exact(2), try_call);
+  set_native(frame, "exit", Range(0, 1), exit);
+  set_native(frame, "command-line-arguments", Exact(0), |evaluator, _| {
+    Ok(evaluator.arguments().to_vec().into_expr())
+  });
 
-  frame
+  set_native(frame, "eval", Range(1, 2), eval);
+  set_function(frame, "read-string", read_string);
+  set_native(frame, "current-environment", Exact(0), current_environment);
+  set_native(frame, "new-environment", Exact(0), new_environment);
 }
 
-pub fn print(arguments: Vec<Expr>) -> Result<Expr, EvalError> {
-  use EvalError::*;
+fn add_console(frame: &mut Frame) {
+  set_native(frame, "print", Arity::Exact(1), print);
+  set_native(frame, "display", Arity::Range(1, 2), display);
+  set_native(frame, "write", Arity::Range(1, 2), write);
+  set_native(frame, "write-string", Arity::Range(1, 2), write_string);
+  set_native(frame, "newline", Arity::Range(0, 1), newline);
+  set_native(frame, "format", Arity::AtLeast(2), format);
+
+  set_native(
+    frame,
+    "current-input-port",
+    Arity::Exact(0),
+    |evaluator, _| {
+      evaluator.capabilities().check(Capability::Console)?;
+      Ok(Port::stdin().into_expr())
+    },
+  );
+  set_native(
+    frame,
+    "current-output-port",
+    Arity::Exact(0),
+    |evaluator, _| {
+      evaluator.capabilities().check(Capability::Console)?;
+      Ok(Port::stdout().into_expr())
+    },
+  );
+  set_native(
+    frame,
+    "current-error-port",
+    Arity::Exact(0),
+    |evaluator, _| {
+      evaluator.capabilities().check(Capability::Console)?;
+      Ok(Port::stderr().into_expr())
+    },
+  );
+}
 
-  if arguments.len() != 1 {
-    return Err(WrongArity);
-  }
+fn add_fs(frame: &mut Frame) {
+  use Capability::*;
+
+  set_native(frame, "load", Arity::Exact(1), load);
+
+  set_fs_function(frame, "read-file", FsRead, |path| {
+    Ok(fs::read_to_string(path)?.into_expr())
+  });
+  set_fs_function(frame, "read-lines", FsRead, |path| {
+    let lines = fs::read_to_string(path)?
+      .lines()
+      .map(String::from)
+      .collect::<Vec<String>>();
+    Ok(lines.into_expr())
+  });
+  set_fs_function(frame, "file-exists?", FsRead, |path| {
+    Ok(path.exists().into_expr())
+  });
+  set_fs_function(frame, "list-directory", FsRead, |path| {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(path)? {
+      names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    names.sort();
+    Ok(names.into_expr())
+  });
+  set_fs_function(frame, "open-input-file", FsRead, |path| {
+    Ok(Port::input(BufReader::new(File::open(path)?)).into_expr())
+  });
+  set_fs_function(frame, "open-output-file", FsWrite, |path| {
+    Ok(Port::output(BufWriter::new(File::create(path)?)).into_expr())
+  });
+  set_fs_function(frame, "delete-file", FsWrite, |path| {
+    fs::remove_file(path)?;
+    Ok(().into_expr())
+  });
+
+  set_native(
+    frame,
+    "write-file",
+    Arity::Exact(2),
+    |evaluator, arguments| {
+      let (path, contents) = fs_arguments(evaluator, arguments)?;
+      fs::write(path, contents)?;
+      Ok(().into_expr())
+    },
+  );
+  set_native(
+    frame,
+    "append-file",
+    Arity::Exact(2),
+    |evaluator, arguments| {
+      let (path, contents) = fs_arguments(evaluator, arguments)?;
+      OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(contents.as_bytes())?;
+      Ok(().into_expr())
+    },
+  );
+}
+
+fn set_native<F>(frame: &mut Frame, name: &str, arity: Arity, function: F)
+where
+  F: Fn(&mut Evaluator, Vec<Expr>) -> Result<Expr, EvalError> + 'static,
+{
+  let native = Native::new(name, arity, function);
+  frame.set(Symbol::new(name), Expr::Atom(Atom::Native(native)));
+}
+
+fn add_env(frame: &mut Frame) {
+  set_native(frame, "getenv", Arity::Exact(1), getenv);
+  set_native(frame, "setenv", Arity::Exact(2), setenv);
+  set_native(
+    frame,
+    "current-directory",
+    Arity::Exact(0),
+    |evaluator, _| {
+      evaluator.capabilities().check(Capability::Env)?;
+      let directory = std::env::current_dir()?;
+      Ok(directory.to_string_lossy().into_owned().into_expr())
+    },
+  );
+}
+
+fn add_process(frame: &mut Frame) {
+  set_native(frame, "run-process", Arity::AtLeast(1), run_process);
+}
+
+// Sets a native which takes a path and needs a capability to access it.
+fn set_fs_function<F>(
+  frame: &mut Frame,
+  name: &str,
+  capability: Capability,
+  function: F,
+) where
+  F: Fn(PathBuf) -> Result<Expr, EvalError> + 'static,
+{
+  set_native(frame, name, Arity::Exact(1), move |evaluator, arguments| {
+    let path = String::from_expr(arguments.into_iter().next().unwrap())?;
+    function(resolve_path(evaluator, &path, capability)?)
+  });
+}
+
+// Sets a native which reads from a port, or from stdin if none is given.
+fn set_input_native<F>(frame: &mut Frame, name: &str, function: F)
+where
+  F: Fn(Port) -> Result<Expr, EvalError> + 'static,
+{
+  set_native(
+    frame,
+    name,
+    Arity::Range(0, 1),
+    move |evaluator, arguments| {
+      let port = match arguments.into_iter().next() {
+        Some(port) => Port::from_expr(port)?,
+        None => {
+          evaluator.capabilities().check(Capability::Console)?;
+          Port::stdin()
+        }
+      };
+
+      function(port)
+    },
+  );
+}
+
+fn set_function<F, Args>(frame: &mut Frame, name: &str, function: F)
+where
+  F: IntoNative<Args>,
+{
+  let native = Native::from_fn(name, function);
+  frame.set(Symbol::new(name), Expr::Atom(Atom::Native(native)));
+}
 
-  let expr = arguments.get(0).unwrap().clone();
+pub fn print(
+  evaluator: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  let expr = arguments.into_iter().next().unwrap();
 
-  println!("{}", expr);
+  output(evaluator, None, &format!("{}\n", expr))?;
 
   Ok(expr)
 }
 
-fn head(arguments: Vec<Expr>) -> Result<Expr, EvalError> {
-  use EvalError::*;
+// Writes the human form of an expression, without a newline, to the port if
+// one is given.
+pub fn display(
+  evaluator: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  let mut arguments = arguments.into_iter();
+  let expr = arguments.next().unwrap();
+  let port = arguments.next().map(Port::from_expr).transpose()?;
+
+  output(evaluator, port, &expr.to_display_string())?;
+  Ok(Expr::List(List::Nil))
+}
 
-  if arguments.len() != 1 {
-    return Err(WrongArity);
-  }
+// Writes the readable form of an expression, like `display`.
+pub fn write(
+  evaluator: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  let mut arguments = arguments.into_iter();
+  let expr = arguments.next().unwrap();
+  let port = arguments.next().map(Port::from_expr).transpose()?;
+
+  output(evaluator, port, &expr.to_string())?;
+  Ok(Expr::List(List::Nil))
+}
 
-  let list = match arguments.get(0) {
-    Some(Expr::List(list)) => list,
-    _ => return Err(InvalidType),
-  };
+// Writes a string as it is, like `display` but only for strings.
+pub fn write_string(
+  evaluator: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  let mut arguments = arguments.into_iter();
+  let string = String::from_expr(arguments.next().unwrap())?;
+  let port = arguments.next().map(Port::from_expr).transpose()?;
+
+  output(evaluator, port, &string)?;
+  Ok(Expr::List(List::Nil))
+}
+
+pub fn newline(
+  evaluator: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  let port = arguments
+    .into_iter()
+    .next()
+    .map(Port::from_expr)
+    .transpose()?;
+
+  output(evaluator, port, "\n")?;
+  Ok(Expr::List(List::Nil))
+}
 
-  let head = match list {
-    ast::List::Cons(node) => node.head.clone(),
-    ast::List::Nil => return Err(InvalidType),
+// Formats a template with `env::format`. The result is returned as a string
+// when the destination is false, written to stdout when it is true and
+// written to the destination when it is a port.
+pub fn format(
+  evaluator: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  let mut arguments = arguments.into_iter();
+
+  let destination = arguments.next().unwrap();
+  let template = String::from_expr(arguments.next().unwrap())?;
+  let string = env::format(&template, arguments.collect())?;
+
+  let port = match destination {
+    Expr::Atom(Atom::Bool(false)) => return Ok(string.into_expr()),
+    Expr::Atom(Atom::Bool(true)) => None,
+    Expr::Atom(Atom::Port(port)) => Some(port),
+    _ => return Err(EvalError::InvalidType),
   };
 
-  Ok(head)
+  output(evaluator, port, &string)?;
+  Ok(Expr::List(List::Nil))
 }
 
-fn tail(arguments: Vec<Expr>) -> Result<Expr, EvalError> {
-  use EvalError::*;
-
-  if arguments.len() != 1 {
-    return Err(WrongArity);
+// Writes to the port, or otherwise to where output is redirected. Only
+// writing to stdout needs the console capability, as ports are checked when
+// they are opened.
+fn output(
+  evaluator: &mut Evaluator,
+  port: Option<Port>,
+  string: &str,
+) -> Result<(), EvalError> {
+  if let Some(port) = port.as_ref().or_else(|| evaluator.output()) {
+    return port.write_str(string);
   }
 
-  let list = match arguments.get(0) {
-    Some(Expr::List(list)) => list,
-    _ => return Err(InvalidType),
-  };
+  evaluator.capabilities().check(Capability::Console)?;
 
-  let tail = match list {
-    ast::List::Cons(node) => node.tail.clone(),
-    ast::List::Nil => return Err(InvalidType),
-  };
+  let mut stdout = io::stdout();
+  stdout.write_all(string.as_bytes())?;
+  stdout.flush()?;
 
-  Ok(Expr::List(tail))
+  Ok(())
 }
 
-fn cons(arguments: Vec<Expr>) -> Result<Expr, EvalError> {
-  use EvalError::*;
+fn head(list: List) -> Result<Expr, EvalError> {
+  match list {
+    List::Cons(node) => Ok(node.head.clone()),
+    List::Nil => Err(EvalError::InvalidType),
+  }
+}
 
-  if arguments.len() != 2 {
-    return Err(WrongArity);
+fn tail(list: List) -> Result<List, EvalError> {
+  match list {
+    List::Cons(node) => Ok(node.tail.clone()),
+    List::Nil => Err(EvalError::InvalidType),
   }
+}
 
-  let head = arguments.get(0).unwrap().clone();
+fn cons(head: Expr, tail: List) -> List {
+  List::cons(head, tail)
+}
 
-  let tail = match arguments.get(1) {
-    Some(Expr::List(list)) => list.clone(),
+// Sorts a list with a comparison function, keeping the order of equal items.
+fn sort(
+  evaluator: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  use EvalError::*;
+
+  let mut arguments = arguments.into_iter();
+
+  let items: Vec<Expr> = match arguments.next() {
+    Some(Expr::List(list)) => list.into_iter().collect(),
     _ => return Err(InvalidType),
   };
+  let less = arguments.next().unwrap();
 
-  Ok(Expr::List(List::cons(head, tail)))
+  let items = merge_sort(evaluator, &less, items)?;
+
+  Ok(Expr::List(
+    items
+      .into_iter()
+      .rev()
+      .fold(List::Nil, |list, item| List::cons(item, list)),
+  ))
 }
 
-pub fn is_number(arguments: Vec<Expr>) -> Result<Expr, EvalError> {
-  use EvalError::*;
+fn merge_sort(
+  evaluator: &mut Evaluator,
+  less: &Expr,
+  mut items: Vec<Expr>,
+) -> Result<Vec<Expr>, EvalError> {
+  if items.len() < 2 {
+    return Ok(items);
+  }
 
-  if arguments.len() != 1 {
-    return Err(WrongArity);
+  let right = items.split_off(items.len() / 2);
+  let mut left = merge_sort(evaluator, less, items)?.into_iter().peekable();
+  let mut right = merge_sort(evaluator, less, right)?.into_iter().peekable();
+
+  let mut merged = Vec::new();
+  while let (Some(first), Some(second)) = (left.peek(), right.peek()) {
+    let ordered = evaluator
+      .call(less, vec![second.clone(), first.clone()])?
+      .is_truthy();
+
+    if ordered {
+      merged.push(right.next().unwrap());
+    } else {
+      merged.push(left.next().unwrap());
+    }
   }
+  merged.extend(left);
+  merged.extend(right);
 
-  let expr = arguments.get(0).unwrap().clone();
+  Ok(merged)
+}
 
-  if let Expr::Atom(Atom::Number(_)) = expr {
-    Ok(Expr::Atom(Atom::Symbol(SYMBOL_TRUE.clone())))
-  } else {
-    Ok(Expr::List(List::Nil))
-  }
+fn integer_to_char(code: u32) -> Result<char, EvalError> {
+  std::char::from_u32(code).ok_or(EvalError::InvalidType)
 }
 
-pub fn is_string(arguments: Vec<Expr>) -> Result<Expr, EvalError> {
-  use EvalError::*;
+// Characters whose case maps to several characters are left as they are.
+fn char_upcase(char: char) -> char {
+  let mut upper = char.to_uppercase();
+  match (upper.next(), upper.next()) {
+    (Some(upper), None) => upper,
+    _ => char,
+  }
+}
 
-  if arguments.len() != 1 {
-    return Err(WrongArity);
+fn char_downcase(char: char) -> char {
+  let mut lower = char.to_lowercase();
+  match (lower.next(), lower.next()) {
+    (Some(lower), None) => lower,
+    _ => char,
   }
+}
+
+fn string_ref(string: String, index: usize) -> Result<char, EvalError> {
+  string
+    .chars()
+    .nth(index)
+    .ok_or(EvalError::OutOfBounds(index))
+}
 
-  let expr = arguments.get(0).unwrap().clone();
+fn string_append(
+  _: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  let strings = arguments
+    .into_iter()
+    .map(String::from_expr)
+    .collect::<Result<Vec<String>, EvalError>>()?;
 
-  if let Expr::Atom(Atom::String(_)) = expr {
-    Ok(Expr::Atom(Atom::Symbol(SYMBOL_TRUE.clone())))
-  } else {
-    Ok(Expr::List(List::Nil))
-  }
+  Ok(Expr::Atom(Atom::String(strings.concat())))
 }
 
-pub fn is_symbol(arguments: Vec<Expr>) -> Result<Expr, EvalError> {
-  use EvalError::*;
+// Returns the characters from the start index up to the end index, or up to
+// the end of the string if it isn't given.
+fn substring(
+  _: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  let mut arguments = arguments.into_iter();
+
+  let string = String::from_expr(arguments.next().unwrap())?;
+  let start = usize::from_expr(arguments.next().unwrap())?;
+  let length = string.chars().count();
+  let end = match arguments.next() {
+    Some(end) => usize::from_expr(end)?,
+    None => length,
+  };
 
-  if arguments.len() != 1 {
-    return Err(WrongArity);
+  if end > length {
+    return Err(EvalError::OutOfBounds(end));
+  }
+  if start > end {
+    return Err(EvalError::OutOfBounds(start));
   }
 
-  let expr = arguments.get(0).unwrap().clone();
+  let substring: String =
+    string.chars().skip(start).take(end - start).collect();
 
-  if let Expr::Atom(Atom::Symbol(_)) = expr {
-    Ok(Expr::Atom(Atom::Symbol(SYMBOL_TRUE.clone())))
-  } else {
-    Ok(Expr::List(List::Nil))
-  }
+  Ok(Expr::Atom(Atom::String(substring)))
 }
 
-pub fn is_function(arguments: Vec<Expr>) -> Result<Expr, EvalError> {
-  use EvalError::*;
-
-  if arguments.len() != 1 {
-    return Err(WrongArity);
+// An empty separator splits the string into its characters.
+fn string_split(string: String, separator: String) -> Vec<String> {
+  if separator.is_empty() {
+    return string.chars().map(String::from).collect();
   }
 
-  let expr = arguments.get(0).unwrap().clone();
+  string.split(&separator).map(String::from).collect()
+}
+
+// Returns false if the string isn't a number.
+fn string_to_number(string: String) -> Option<f64> {
+  read::string_to_number(&string)
+}
 
-  if let Expr::Atom(Atom::Function(_)) = expr {
-    Ok(Expr::Atom(Atom::Symbol(SYMBOL_TRUE.clone())))
+fn vector_ref(vector: Vector, index: usize) -> Result<Expr, EvalError> {
+  vector.get(index).ok_or(EvalError::OutOfBounds(index))
+}
+
+fn vector_set(
+  vector: Vector,
+  index: usize,
+  expr: Expr,
+) -> Result<Expr, EvalError> {
+  if vector.set(index, expr.clone()) {
+    Ok(expr)
   } else {
-    Ok(Expr::List(List::Nil))
+    Err(EvalError::OutOfBounds(index))
   }
 }
 
-pub fn is_special(arguments: Vec<Expr>) -> Result<Expr, EvalError> {
-  use EvalError::*;
+// Builds a map from alternating keys and values.
+fn hash_map(
+  _: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  if !arguments.len().is_multiple_of(2) {
+    return Err(EvalError::WrongArity);
+  }
 
-  if arguments.len() != 1 {
-    return Err(WrongArity);
+  let mut map = Map::new();
+  let mut arguments = arguments.into_iter();
+  while let (Some(key), Some(value)) = (arguments.next(), arguments.next()) {
+    map = map.insert(Key::from_expr(key)?, value);
   }
 
-  let expr = arguments.get(0).unwrap().clone();
+  Ok(Expr::Atom(Atom::Map(map)))
+}
 
-  if let Expr::Atom(Atom::Special(_)) = expr {
-    Ok(Expr::Atom(Atom::Symbol(SYMBOL_TRUE.clone())))
-  } else {
-    Ok(Expr::List(List::Nil))
-  }
+// Returns the value for a key, or the default (false if not given) when the
+// key is missing.
+fn map_get(_: &mut Evaluator, arguments: Vec<Expr>) -> Result<Expr, EvalError> {
+  let mut arguments = arguments.into_iter();
+
+  let map = Map::from_expr(arguments.next().unwrap())?;
+  let key = Key::from_expr(arguments.next().unwrap())?;
+  let default = arguments.next().unwrap_or(Expr::Atom(Atom::Bool(false)));
+
+  Ok(map.get(&key).cloned().unwrap_or(default))
 }
 
-pub fn is_native(arguments: Vec<Expr>) -> Result<Expr, EvalError> {
-  use EvalError::*;
+// Entries of the second map replace those of the first.
+fn map_merge(left: Map, right: Map) -> Map {
+  right
+    .entries()
+    .into_iter()
+    .fold(left, |map, (key, value)| map.insert(key, value))
+}
+
+pub fn is_boolean(expr: Expr) -> bool {
+  matches!(expr, Expr::Atom(Atom::Bool(_)))
+}
+
+pub fn is_number(expr: Expr) -> bool {
+  matches!(expr, Expr::Atom(Atom::Number(_)))
+}
+
+pub fn is_string(expr: Expr) -> bool {
+  matches!(expr, Expr::Atom(Atom::String(_)))
+}
+
+pub fn is_symbol(expr: Expr) -> bool {
+  matches!(expr, Expr::Atom(Atom::Symbol(_)))
+}
+
+pub fn is_keyword(expr: Expr) -> bool {
+  matches!(expr, Expr::Atom(Atom::Keyword(_)))
+}
+
+pub fn is_char(expr: Expr) -> bool {
+  matches!(expr, Expr::Atom(Atom::Char(_)))
+}
+
+pub fn is_vector(expr: Expr) -> bool {
+  matches!(expr, Expr::Atom(Atom::Vector(_)))
+}
 
-  if arguments.len() != 1 {
-    return Err(WrongArity);
+pub fn is_map(expr: Expr) -> bool {
+  matches!(expr, Expr::Atom(Atom::Map(_)))
+}
+
+pub fn is_function(expr: Expr) -> bool {
+  matches!(expr, Expr::Atom(Atom::Function(_)))
+}
+
+pub fn is_special(expr: Expr) -> bool {
+  matches!(expr, Expr::Atom(Atom::Special(_)))
+}
+
+pub fn is_native(expr: Expr) -> bool {
+  matches!(expr, Expr::Atom(Atom::Native(_)))
+}
+
+pub fn is_environment(expr: Expr) -> bool {
+  matches!(expr, Expr::Atom(Atom::Environment(_)))
+}
+
+pub fn load(
+  evaluator: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  let path = String::from_expr(arguments.into_iter().next().unwrap())?;
+  let path = resolve_path(evaluator, &path, Capability::FsRead)?;
+
+  evaluator.eval_file(path)
+}
+
+// Paths are relative to the file being evaluated, like imports.
+fn resolve_path(
+  evaluator: &Evaluator,
+  path: &str,
+  capability: Capability,
+) -> Result<PathBuf, EvalError> {
+  let path = match evaluator.path().and_then(|current| current.parent()) {
+    Some(directory) => directory.join(path),
+    None => PathBuf::from(path),
+  };
+
+  evaluator.capabilities().check_path(&path, capability)
+}
+
+// Takes the path and contents passed to a native that writes a file.
+fn fs_arguments(
+  evaluator: &Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<(PathBuf, String), EvalError> {
+  let mut arguments = arguments.into_iter();
+
+  let path = String::from_expr(arguments.next().unwrap())?;
+  let contents = String::from_expr(arguments.next().unwrap())?;
+
+  Ok((
+    resolve_path(evaluator, &path, Capability::FsWrite)?,
+    contents,
+  ))
+}
+
+fn or_eof<T>(value: Option<T>) -> Expr
+where
+  T: IntoExpr,
+{
+  match value {
+    Some(value) => value.into_expr(),
+    None => Expr::Atom(Atom::Eof),
   }
+}
 
-  let expr = arguments.get(0).unwrap().clone();
+// Calls the thunk with output redirected to a string, which is returned.
+fn with_output_to_string(
+  evaluator: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  let thunk = arguments.into_iter().next().unwrap();
 
-  if let Expr::Atom(Atom::Native(_)) = expr {
-    Ok(Expr::Atom(Atom::Symbol(SYMBOL_TRUE.clone())))
-  } else {
-    Ok(Expr::List(List::Nil))
+  let port = Port::output_string();
+  let original = evaluator.set_output(Some(port.clone()));
+  let result = evaluator.call(&thunk, vec![]);
+  evaluator.set_output(original);
+  result?;
+
+  Ok(port.output_string_contents().unwrap().into_expr())
+}
+
+// Calls the thunk, and if it fails, calls the handler with the error message
+// instead. Errors from limits and interrupts can't be caught.
+fn try_call(
+  evaluator: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  let mut arguments = arguments.into_iter();
+  let thunk = arguments.next().unwrap();
+  let handler = arguments.next().unwrap();
+
+  match evaluator.call(&thunk, vec![]) {
+    Err(error) if error.is_catchable() => {
+      evaluator.call(&handler, vec![error.to_string().into_expr()])
+    }
+    result => result,
   }
 }
 
-pub fn sqrt(arguments: Vec<Expr>) -> Result<Expr, EvalError> {
-  use EvalError::*;
+// Stops the program with a status code from 0 to 255, which is 0 if not
+// given. Larger codes would be truncated, possibly to 0.
+fn exit(_: &mut Evaluator, arguments: Vec<Expr>) -> Result<Expr, EvalError> {
+  let code = match arguments.into_iter().next() {
+    Some(code) => u8::from_expr(code)?,
+    None => 0,
+  };
 
-  if arguments.len() != 1 {
-    return Err(WrongArity);
+  Err(EvalError::Exit(code as i32))
+}
+
+// Returns the value of an environment variable, or false if it isn't set.
+fn getenv(
+  evaluator: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  evaluator.capabilities().check(Capability::Env)?;
+
+  let name = String::from_expr(arguments.into_iter().next().unwrap())?;
+
+  Ok(std::env::var(name).ok().into_expr())
+}
+
+fn setenv(
+  evaluator: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  evaluator.capabilities().check(Capability::Env)?;
+
+  let mut arguments = arguments.into_iter();
+  let name = String::from_expr(arguments.next().unwrap())?;
+  let value = String::from_expr(arguments.next().unwrap())?;
+
+  // Setting a variable like this panics, so it is an error instead.
+  if name.is_empty()
+    || name.contains('=')
+    || name.contains('\0')
+    || value.contains('\0')
+  {
+    let error = io::Error::new(
+      io::ErrorKind::InvalidInput,
+      "invalid environment variable",
+    );
+    return Err(EvalError::Io(error));
   }
 
-  let number = match arguments.get(0) {
-    Some(Expr::Atom(Atom::Number(number))) => number,
-    _ => return Err(InvalidType),
+  std::env::set_var(name, value);
+
+  Ok(().into_expr())
+}
+
+// Runs a program with the arguments and waits for it to finish, returning a
+// map of its `:status`, `:stdout` and `:stderr`. The status is false if the
+// program was stopped by a signal.
+fn run_process(
+  evaluator: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  evaluator.capabilities().check(Capability::Process)?;
+
+  let mut arguments = arguments
+    .into_iter()
+    .map(String::from_expr)
+    .collect::<Result<Vec<String>, EvalError>>()?
+    .into_iter();
+  let program = arguments.next().unwrap();
+
+  let output = Command::new(program).args(arguments).output()?;
+
+  let status = output.status.code().into_expr();
+  let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+  let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+
+  let map = Map::new()
+    .insert(Key::Keyword(Keyword::new("status")), status)
+    .insert(Key::Keyword(Keyword::new("stdout")), stdout.into_expr())
+    .insert(Key::Keyword(Keyword::new("stderr")), stderr.into_expr());
+
+  Ok(map.into_expr())
+}
+
+pub fn eval(
+  evaluator: &mut Evaluator,
+  arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  use EvalError::*;
+
+  let frame = match arguments.get(1) {
+    Some(Expr::Atom(Atom::Environment(frame))) => frame.clone(),
+    Some(_) => return Err(InvalidType),
+    None => evaluator.frame(),
   };
 
-  Ok(Expr::Atom(Atom::Number(number.sqrt())))
+  let expr = arguments.into_iter().next().unwrap();
+
+  evaluator.eval_expr_in(frame, expr)
+}
+
+pub fn read_string(source: String) -> Result<Expr, EvalError> {
+  let expr = Reader::new(source.chars()).read_expr()?;
+
+  Ok(expr)
+}
+
+pub fn current_environment(
+  evaluator: &mut Evaluator,
+  _arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  Ok(Expr::Atom(Atom::Environment(evaluator.frame())))
+}
+
+pub fn new_environment(
+  evaluator: &mut Evaluator,
+  _arguments: Vec<Expr>,
+) -> Result<Expr, EvalError> {
+  Ok(Expr::Atom(Atom::Environment(Frame::with_parent(
+    evaluator.base(),
+  ))))
 }
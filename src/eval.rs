@@ -1,13 +1,24 @@
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fs, io, mem};
 
 use thiserror::Error;
 
 use crate::ast::{
-  self, Atom, Expr, Function, List, Macro, Native, Operator, Special, Symbol,
-  SYMBOL_TRUE,
+  self, Arity, Atom, Expr, FromExpr, Function, IntoNative, Key, List, Macro,
+  Map, Native, Operator, Port, Special, Symbol, Vector,
 };
-use crate::env::Frame;
-use crate::read;
+use crate::check::{Checker, Warning};
+use crate::env::{self, Capabilities, Capability, Frame};
+use crate::module::{self, Import, Module};
+use crate::read::{self, ReadError};
+use crate::resolve::{self, Address, Call, Code, Resolver};
 
 pub fn eval(expr: Expr) -> Result<Expr, EvalError> {
   let mut evalutor = Evaluator::new();
@@ -15,275 +26,861 @@ pub fn eval(expr: Expr) -> Result<Expr, EvalError> {
 }
 
 pub struct Evaluator {
+  base: Frame,
   frame: Frame,
+  // Path of the file being evaluated, if any.
+  path: Option<PathBuf>,
+  search_path: Vec<PathBuf>,
+  // Arguments passed to the program, returned by `command-line-arguments`.
+  arguments: Vec<String>,
+  modules: HashMap<PathBuf, Module>,
+  loading: Vec<PathBuf>,
+  limits: Limits,
+  depth: usize,
+  // Whether an evaluation started by the host is in progress.
+  running: bool,
+  steps: u64,
+  // Allocations made while this evaluator was running since the budget was
+  // last reset.
+  allocations: Rc<Cell<u64>>,
+  deadline: Option<Instant>,
+  interrupt: Arc<AtomicBool>,
+  capabilities: Capabilities,
+  // Port that output is redirected to, instead of stdout.
+  output: Option<Port>,
+  // Number of live frames at which garbage is next collected.
+  collect_threshold: usize,
 }
 
-impl Evaluator {
-  pub fn new() -> Evaluator {
-    let mut evaluator = Evaluator {
-      frame: Frame::base(),
-    };
+// Calls nest this deep by default when any other limit is set, which fits in
+// the stack of the main thread.
+const DEFAULT_MAX_DEPTH: usize = 500;
+
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+  // Maximum number of nested function and macro calls.
+  pub max_depth: Option<usize>,
+  // Maximum number of evaluation steps.
+  pub fuel: Option<u64>,
+  // Maximum number of lists, frames and closures allocated.
+  pub max_allocations: Option<u64>,
+  // Maximum time spent evaluating.
+  pub timeout: Option<Duration>,
+}
 
-    // Inject standard library.
-    let expr = read::read(include_str!("lib.zuko")).unwrap();
-    evaluator.eval_expr(expr).unwrap();
+pub struct EvaluatorBuilder {
+  prelude: bool,
+  // Source of the standard library, if it replaces the bundled one.
+  stdlib: Option<String>,
+  bundled_stdlib: bool,
+  natives: Vec<Native>,
+  preload: Vec<PathBuf>,
+  search_path: Vec<PathBuf>,
+  arguments: Vec<String>,
+  limits: Limits,
+  capabilities: Capabilities,
+}
 
-    evaluator
-  }
+impl Limits {
+  // Returns the maximum depth of calls. Limits on anything else are for
+  // untrusted code, which mustn't be able to overflow the stack either.
+  pub fn depth_limit(&self) -> Option<usize> {
+    if self.max_depth.is_some() {
+      return self.max_depth;
+    }
 
-  pub fn eval_expr(&mut self, expr: Expr) -> Result<Expr, EvalError> {
-    use Expr::*;
+    if self.fuel.is_some()
+      || self.max_allocations.is_some()
+      || self.timeout.is_some()
+    {
+      Some(DEFAULT_MAX_DEPTH)
+    } else {
+      None
+    }
+  }
+}
 
-    match expr {
-      List(list) => self.eval_list(list),
-      Atom(atom) => self.eval_atom(atom),
+impl EvaluatorBuilder {
+  pub fn new() -> EvaluatorBuilder {
+    EvaluatorBuilder {
+      prelude: true,
+      stdlib: None,
+      bundled_stdlib: true,
+      natives: Vec::new(),
+      preload: Vec::new(),
+      search_path: Vec::new(),
+      arguments: Vec::new(),
+      limits: Limits::default(),
+      capabilities: Capabilities::all(),
     }
   }
 
-  pub fn eval_list(&mut self, list: List) -> Result<Expr, EvalError> {
-    use Atom::*;
-    use EvalError::{NotCallable, WrongArity};
-    use List::*;
+  // Registers a native in the base frame, replacing any existing binding.
+  pub fn native<S, F>(mut self, name: S, arity: Arity, function: F) -> Self
+  where
+    S: Into<String>,
+    F: Fn(&mut Evaluator, Vec<Expr>) -> Result<Expr, EvalError> + 'static,
+  {
+    self.natives.push(Native::new(name, arity, function));
+    self
+  }
 
-    let node = match &list {
-      Cons(node) => node.as_ref(),
-      Nil => return Ok(Expr::List(Nil)),
+  // Registers an ordinary Rust function as a native.
+  pub fn function<F, Args>(mut self, name: &str, function: F) -> Self
+  where
+    F: IntoNative<Args>,
+  {
+    self.natives.push(Native::from_fn(name, function));
+    self
+  }
+
+  // Starts from an empty base frame instead of the built-in natives. The
+  // bundled standard library needs them, so it is skipped too, though one
+  // given with `stdlib` is still loaded.
+  pub fn without_prelude(mut self) -> Self {
+    self.prelude = false;
+    self
+  }
+
+  pub fn without_stdlib(mut self) -> Self {
+    self.stdlib = None;
+    self.bundled_stdlib = false;
+    self
+  }
+
+  // Replaces the bundled standard library with the given source.
+  pub fn stdlib<S>(mut self, source: S) -> Self
+  where
+    S: Into<String>,
+  {
+    self.stdlib = Some(source.into());
+    self.bundled_stdlib = false;
+    self
+  }
+
+  // Evaluates a file once the evaluator is built, in the order added.
+  pub fn preload<P>(mut self, path: P) -> Self
+  where
+    P: Into<PathBuf>,
+  {
+    self.preload.push(path.into());
+    self
+  }
+
+  pub fn search_path<P>(mut self, path: P) -> Self
+  where
+    P: Into<PathBuf>,
+  {
+    self.search_path.push(path.into());
+    self
+  }
+
+  pub fn arguments<I, S>(mut self, arguments: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+  {
+    self.arguments = arguments.into_iter().map(Into::into).collect();
+    self
+  }
+
+  pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+    self.capabilities = capabilities;
+    self
+  }
+
+  pub fn limits(mut self, limits: Limits) -> Self {
+    self.limits = limits;
+    self
+  }
+
+  pub fn max_depth(mut self, max_depth: usize) -> Self {
+    self.limits.max_depth = Some(max_depth);
+    self
+  }
+
+  pub fn fuel(mut self, fuel: u64) -> Self {
+    self.limits.fuel = Some(fuel);
+    self
+  }
+
+  pub fn max_allocations(mut self, max_allocations: u64) -> Self {
+    self.limits.max_allocations = Some(max_allocations);
+    self
+  }
+
+  pub fn timeout(mut self, timeout: Duration) -> Self {
+    self.limits.timeout = Some(timeout);
+    self
+  }
+
+  pub fn build(self) -> Result<Evaluator, EvalError> {
+    let mut base = if self.prelude {
+      Frame::base()
+    } else {
+      Frame::new()
     };
 
-    let head = node.head.clone();
-    let tail = node.tail.clone();
+    for native in self.natives.into_iter() {
+      base.set(native.name().clone(), Expr::Atom(Atom::Native(native)));
+    }
 
-    let head = self.eval_expr(head)?;
+    let mut evaluator = Evaluator {
+      base: base.clone(),
+      frame: base.clone(),
+      path: None,
+      search_path: self.search_path,
+      arguments: self.arguments,
+      modules: HashMap::new(),
+      loading: Vec::new(),
+      limits: Limits::default(),
+      depth: 0,
+      running: false,
+      steps: 0,
+      allocations: Rc::new(Cell::new(0)),
+      deadline: None,
+      interrupt: Arc::new(AtomicBool::new(false)),
+      capabilities: Capabilities::all(),
+      output: None,
+      collect_threshold: 1024,
+    };
 
-    let function = match head {
-      Expr::Atom(Function(function)) => function,
-      Expr::Atom(Macro(macr)) => return self.eval_call_macro(macr, tail),
-      Expr::Atom(Native(native)) => return self.eval_call_native(native, tail),
-      Expr::Atom(Special(special)) => {
-        return self.eval_call_special(special, tail)
+    // Inject standard library.
+    let stdlib = match self.stdlib {
+      Some(source) => Some(source),
+      None if self.bundled_stdlib && self.prelude => {
+        Some(include_str!("lib.zuko").to_string())
       }
-      _ => return Err(NotCallable),
+      None => None,
     };
+    if let Some(source) = stdlib {
+      let expr = read::read(&source)?;
+      evaluator.eval_expr(expr)?;
+    }
 
-    if tail.len() != function.parameters().len() {
-      return Err(WrongArity);
+    evaluator.frame = Frame::with_parent(base);
+
+    for path in self.preload.iter() {
+      evaluator.eval_file(path)?;
     }
 
-    let arguments = tail
-      .into_iter()
-      .map(|expr| self.eval_expr(expr))
-      .collect::<Result<Vec<Expr>, EvalError>>()?;
+    // The standard library and preloaded files are trusted, so they don't
+    // count against limits.
+    evaluator.limits = self.limits;
+    evaluator.capabilities = self.capabilities;
+    evaluator.reset_budget();
+
+    Ok(evaluator)
+  }
+}
+
+impl Default for EvaluatorBuilder {
+  fn default() -> EvaluatorBuilder {
+    EvaluatorBuilder::new()
+  }
+}
+
+impl Evaluator {
+  pub fn new() -> Evaluator {
+    EvaluatorBuilder::new()
+      .build()
+      .expect("standard library failed to load")
+  }
+
+  pub fn builder() -> EvaluatorBuilder {
+    EvaluatorBuilder::new()
+  }
+
+  pub fn arguments(&self) -> &[String] {
+    &self.arguments
+  }
 
-    let original_frame = self.frame.clone();
-    self.frame = Frame::with_parent(function.frame().clone());
+  pub fn capabilities(&self) -> &Capabilities {
+    &self.capabilities
+  }
 
-    let arguments: Vec<(&ast::Symbol, Expr)> = function
-      .parameters()
-      .into_iter()
-      .zip(arguments.into_iter())
-      .collect();
+  pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+    self.capabilities = capabilities;
+  }
 
-    for (name, argument) in arguments {
-      self.frame.set(name.clone(), argument);
+  pub fn limits(&self) -> &Limits {
+    &self.limits
+  }
+
+  pub fn set_limits(&mut self, limits: Limits) {
+    self.limits = limits;
+  }
+
+  // Starts counting steps and allocations from zero again, so that the same
+  // evaluator can run many scripts with a fresh budget each.
+  pub fn reset_budget(&mut self) {
+    self.steps = 0;
+    self.allocations.set(0);
+  }
+
+  // Returns a flag that stops the evaluation in progress when set, from any
+  // thread. It is cleared once the evaluation has stopped.
+  pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+    self.interrupt.clone()
+  }
+
+  // Returns the port that output is redirected to, if any.
+  pub fn output(&self) -> Option<&Port> {
+    self.output.as_ref()
+  }
+
+  // Redirects output to the port, or back to stdout if it is None, returning
+  // the port it was redirected to before.
+  pub fn set_output(&mut self, port: Option<Port>) -> Option<Port> {
+    mem::replace(&mut self.output, port)
+  }
+
+  pub fn steps(&self) -> u64 {
+    self.steps
+  }
+
+  pub fn allocations(&self) -> u64 {
+    self.allocations.get()
+  }
+
+  pub fn add_search_path<P>(&mut self, path: P)
+  where
+    P: Into<PathBuf>,
+  {
+    self.search_path.push(path.into());
+  }
+
+  pub fn frame(&self) -> Frame {
+    self.frame.clone()
+  }
+
+  pub fn base(&self) -> Frame {
+    self.base.clone()
+  }
+
+  pub fn get_global(&self, name: &str) -> Option<Expr> {
+    self.frame.get(&Symbol::new(name))
+  }
+
+  pub fn set_global(&mut self, name: &str, expr: Expr) {
+    self.frame.set(Symbol::new(name), expr);
+  }
+
+  pub(crate) fn path(&self) -> Option<&Path> {
+    self.path.as_deref()
+  }
+
+  pub fn check(&self, expr: &Expr) -> Vec<Warning> {
+    Checker::new(
+      self.frame.clone(),
+      self.path.clone(),
+      self.search_path.clone(),
+    )
+    .check(expr)
+  }
+
+  pub fn check_file<P>(&self, path: P) -> Result<Vec<Warning>, EvalError>
+  where
+    P: AsRef<Path>,
+  {
+    let (path, expr) = read_file(path.as_ref())?;
+
+    let checker =
+      Checker::new(self.frame.clone(), Some(path), self.search_path.clone());
+
+    Ok(checker.check(&expr))
+  }
+
+  pub fn eval_file<P>(&mut self, path: P) -> Result<Expr, EvalError>
+  where
+    P: AsRef<Path>,
+  {
+    let (path, expr) = read_file(path.as_ref())?;
+    self.eval_expr_at(path, expr)
+  }
+
+  pub fn eval_expr(&mut self, expr: Expr) -> Result<Expr, EvalError> {
+    self.run(|evaluator| evaluator.eval_forms(expr))
+  }
+
+  // Runs an evaluation started by the host, counting the allocations made on
+  // this thread until it ends against this evaluator. The timeout starts with
+  // each such evaluation, so time spent idle doesn't count against it, and
+  // garbage is collected once it ends, so that hosts which only `call` into
+  // the evaluator don't leak cycles.
+  fn run<F>(&mut self, f: F) -> Result<Expr, EvalError>
+  where
+    F: FnOnce(&mut Evaluator) -> Result<Expr, EvalError>,
+  {
+    if self.running {
+      return f(self);
+    }
+
+    self.running = true;
+    self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+    let counter = ast::set_allocation_counter(Some(self.allocations.clone()));
+    let result = f(self);
+    ast::set_allocation_counter(counter);
+    self.running = false;
+
+    self.collect_garbage_if_needed();
+
+    result
+  }
+
+  fn eval_forms(&mut self, expr: Expr) -> Result<Expr, EvalError> {
+    // Imports are loaded before the rest of the program is resolved, so
+    // that the symbols they bind are known.
+    if self.frame.scope().is_global() {
+      for import in module::collect_imports(&expr)? {
+        self.import(&import)?;
+      }
+    }
+
+    // Each top-level form is resolved right before it is evaluated, so that
+    // the forms before it can define the globals it references.
+    let mut resolver = Resolver::new(self.frame.scope());
+    resolver.declare(&expr);
+
+    let mut result = Expr::List(List::Nil);
+    for form in resolve::top_level_forms(&expr) {
+      let code = resolver.resolve(&form)?;
+
+      for symbol in resolver.take_free() {
+        if self.frame.get(&symbol).is_none() {
+          return Err(EvalError::UndefinedSymbol(symbol));
+        }
+      }
+
+      result = self.eval_code(&code)?;
+
+      if self.depth == 0 {
+        self.collect_garbage_if_needed();
+      }
     }
 
-    let expr = self.eval_expr(function.body().clone())?;
+    Ok(result)
+  }
+
+  // Frees frames and closures that are only referenced by cycles, returning
+  // how many frames were freed.
+  pub fn collect_garbage(&mut self) -> usize {
+    env::collect()
+  }
+
+  // Collects once the number of live frames has doubled since the last
+  // collection.
+  fn collect_garbage_if_needed(&mut self) {
+    if env::live_frames() >= self.collect_threshold {
+      self.collect_garbage();
+      self.collect_threshold = (env::live_frames() * 2).max(1024);
+    }
+  }
 
+  pub(crate) fn eval_expr_in(
+    &mut self,
+    frame: Frame,
+    expr: Expr,
+  ) -> Result<Expr, EvalError> {
+    let original_frame = mem::replace(&mut self.frame, frame);
+    let result = self.eval_expr(expr);
     self.frame = original_frame;
 
-    Ok(expr)
+    result
   }
 
-  pub fn eval_call_macro(
+  fn eval_expr_at(
     &mut self,
-    macr: Macro,
-    tail: List,
+    path: PathBuf,
+    expr: Expr,
   ) -> Result<Expr, EvalError> {
-    use Expr::*;
+    use EvalError::*;
+
+    if self.loading.contains(&path) {
+      return Err(CircularImport(path));
+    }
+
+    self.loading.push(path.clone());
+    let original_path = self.path.replace(path);
+    let result = self.eval_expr(expr);
+    self.path = original_path;
+    self.loading.pop();
+
+    result
+  }
+
+  fn import(&mut self, import: &Import) -> Result<(), EvalError> {
+    use EvalError::*;
+
+    let path = import.find(self.path.as_deref(), &self.search_path)?;
+    let path = self.capabilities.check_path(&path, Capability::FsRead)?;
+    let module = self.load_module(path)?;
 
-    let original_frame = self.frame.clone();
-    self.frame = Frame::with_parent(original_frame.clone());
+    for (export, name) in import.bindings(&module.exports) {
+      let expr = module.frame.get(&export).ok_or(UndefinedSymbol(export))?;
+      self.frame.set(name, expr);
+    }
+
+    Ok(())
+  }
+
+  fn load_module(&mut self, path: PathBuf) -> Result<Module, EvalError> {
+    use EvalError::*;
 
-    let argument = List(tail);
-    self.frame.set(macr.parameter().clone(), argument);
+    if let Some(module) = self.modules.get(&path) {
+      return Ok(module.clone());
+    }
+
+    let (path, expr) = read_file(&path)?;
+    let exports = module::collect_exports(&expr)?;
 
-    let mut expr = self.eval_expr(macr.body().clone())?;
+    // Each module is evaluated in its own frame on top of the base.
+    let frame = Frame::with_parent(self.base.clone());
 
+    let original_frame = mem::replace(&mut self.frame, frame.clone());
+    let result = self.eval_expr_at(path.clone(), expr);
     self.frame = original_frame;
+    result?;
 
-    expr = self.eval_expr(expr)?;
+    for export in exports.iter() {
+      if frame.get(export).is_none() {
+        return Err(UndefinedSymbol(export.clone()));
+      }
+    }
 
-    Ok(expr)
+    let module = Module { frame, exports };
+    self.modules.insert(path, module.clone());
+
+    Ok(module)
   }
 
-  pub fn eval_call_special(
+  pub fn eval_code(&mut self, code: &Code) -> Result<Expr, EvalError> {
+    self.consume()?;
+
+    match code {
+      Code::Constant(expr) => Ok(expr.clone()),
+      Code::Variable(address) => self.eval_variable(address),
+      Code::Define(index, code) => self.eval_define(*index, code),
+      Code::Begin(codes) => self.eval_begin(codes),
+      Code::Vector(codes) => self.eval_vector(codes),
+      Code::Map(entries) => self.eval_map(entries),
+      Code::Function(lambda) => Ok(Expr::Atom(Atom::Function(Function::new(
+        self.frame.clone(),
+        lambda.clone(),
+      )))),
+      Code::Macro(lambda) => Ok(Expr::Atom(Atom::Macro(Macro::new(
+        self.frame.clone(),
+        lambda.clone(),
+      )))),
+      Code::If(condition, consequent, alternative) => {
+        self.eval_if(condition, consequent, alternative)
+      }
+      Code::Operator(operator, left, right) => {
+        self.eval_operator(operator, left, right)
+      }
+      Code::Call(call) => self.eval_call(call),
+    }
+  }
+
+  pub fn eval_variable(
     &mut self,
-    special: Special,
-    tail: List,
+    address: &Address,
   ) -> Result<Expr, EvalError> {
-    use Special::*;
-
-    match special {
-      Begin => self.eval_call_special_begin(tail),
-      Define => self.eval_call_special_define(tail),
-      Function => self.eval_call_special_function(tail),
-      Macro => self.eval_call_special_macro(tail),
-      If => self.eval_call_special_if(tail),
-      Quote => self.eval_call_special_quote(tail),
-      Operator(operator) => self.eval_call_special_operator(operator, tail),
+    use EvalError::*;
+
+    match self
+      .frame
+      .get_at(address.depth, address.index, &address.symbol)
+    {
+      Some(expr) => Ok(expr),
+      None => Err(UndefinedSymbol(address.symbol.clone())),
     }
   }
 
-  pub fn eval_call_native(
+  pub fn eval_define(
     &mut self,
-    native: Native,
-    tail: List,
+    index: usize,
+    code: &Code,
   ) -> Result<Expr, EvalError> {
-    let arguments = tail
-      .into_iter()
-      .map(|expr| self.eval_expr(expr))
-      .collect::<Result<Vec<Expr>, EvalError>>()?;
+    let expr = self.eval_code(code)?;
 
-    native.call(arguments)
+    self.frame.set_at(index, expr.clone());
+
+    Ok(expr)
   }
 
-  pub fn eval_call_special_begin(
+  pub fn eval_vector(&mut self, codes: &[Code]) -> Result<Expr, EvalError> {
+    let items = self.eval_arguments(codes)?;
+
+    Ok(Expr::Atom(Atom::Vector(Vector::new(items))))
+  }
+
+  pub fn eval_map(
     &mut self,
-    tail: List,
+    entries: &[(Code, Code)],
   ) -> Result<Expr, EvalError> {
-    use EvalError::*;
+    let mut map = Map::new();
 
-    if tail.len() < 1 {
-      return Err(WrongArity);
+    for (key, value) in entries.iter() {
+      let key = Key::from_expr(self.eval_code(key)?)?;
+      let value = self.eval_code(value)?;
+      map = map.insert(key, value);
     }
 
-    let mut tail = tail
-      .into_iter()
-      .map(|expr| self.eval_expr(expr))
-      .collect::<Result<Vec<Expr>, EvalError>>()?;
+    Ok(Expr::Atom(Atom::Map(map)))
+  }
+
+  pub fn eval_begin(&mut self, codes: &[Code]) -> Result<Expr, EvalError> {
+    let mut expr = Expr::List(List::Nil);
+
+    for code in codes.iter() {
+      expr = self.eval_code(code)?;
+    }
 
-    Ok(tail.pop().unwrap())
+    Ok(expr)
   }
 
-  pub fn eval_call_special_define(
+  pub fn eval_if(
     &mut self,
-    tail: List,
+    condition: &Code,
+    consequent: &Code,
+    alternative: &Code,
   ) -> Result<Expr, EvalError> {
+    let condition = self.eval_code(condition)?;
+
+    if condition.is_truthy() {
+      self.eval_code(consequent)
+    } else {
+      self.eval_code(alternative)
+    }
+  }
+
+  pub fn eval_call(&mut self, call: &Call) -> Result<Expr, EvalError> {
     use EvalError::*;
 
-    if tail.len() != 2 {
-      return Err(WrongArity);
+    let head = self.eval_code(&call.head)?;
+
+    match head {
+      Expr::Atom(Atom::Function(function)) => {
+        let arguments = self.resolve_arguments(call)?;
+        self.eval_call_function(function, arguments)
+      }
+      Expr::Atom(Atom::Macro(macr)) => self.eval_call_macro(macr, &call.tail),
+      Expr::Atom(Atom::Native(native)) => {
+        let arguments = self.resolve_arguments(call)?;
+        self.eval_call_native(native, arguments)
+      }
+      Expr::Atom(Atom::Special(special)) => {
+        self.eval_call_special(special, &call.tail)
+      }
+      _ => Err(NotCallable),
     }
+  }
 
-    let symbol = self.as_symbol(tail.get(0).unwrap().clone())?;
-    let expr = self.eval_expr(tail.get(1).unwrap().clone())?;
+  // The call runs in a frame of the scope it was resolved in, so its
+  // arguments resolve the same way each time and are only resolved once.
+  fn resolve_arguments<'a>(
+    &mut self,
+    call: &'a Call,
+  ) -> Result<&'a [Code], EvalError> {
+    if let Some(arguments) = call.arguments.get() {
+      return Ok(arguments);
+    }
 
-    self.frame.set(symbol, expr.clone());
+    let arguments =
+      Resolver::new(self.frame.scope()).resolve_arguments(&call.tail)?;
 
-    Ok(expr)
+    Ok(call.arguments.get_or_init(|| arguments))
   }
 
-  pub fn eval_call_special_function(
+  pub fn eval_call_function(
     &mut self,
-    tail: List,
+    function: Function,
+    arguments: &[Code],
   ) -> Result<Expr, EvalError> {
     use EvalError::*;
 
-    if tail.len() != 2 {
+    if arguments.len() != function.parameters().len() {
       return Err(WrongArity);
     }
 
-    let parameters = self.as_list(tail.get(0).unwrap().clone())?;
-    let body = tail.get(1).unwrap().clone();
+    let arguments = self.eval_arguments(arguments)?;
 
-    let parameters = parameters
-      .into_iter()
-      .map(|expr| self.as_symbol(expr))
-      .collect::<Result<Vec<Symbol>, EvalError>>()?;
-
-    let frame = self.frame.clone();
+    self.apply_function(function, arguments)
+  }
 
-    Ok(Expr::Atom(Atom::Function(Function::new(
-      frame, parameters, body,
-    ))))
+  // Calls a function, native or macro with arguments that are already
+  // evaluated. Macros receive the arguments as their raw terms.
+  pub fn call(
+    &mut self,
+    callee: &Expr,
+    arguments: Vec<Expr>,
+  ) -> Result<Expr, EvalError> {
+    self.run(|evaluator| evaluator.call_with(callee, arguments))
   }
 
-  pub fn eval_call_special_macro(
+  fn call_with(
     &mut self,
-    tail: List,
+    callee: &Expr,
+    arguments: Vec<Expr>,
   ) -> Result<Expr, EvalError> {
     use EvalError::*;
 
-    if tail.len() != 2 {
-      return Err(WrongArity);
-    }
-
-    let parameters = self.as_list(tail.get(0).unwrap().clone())?;
-    let body = tail.get(1).unwrap().clone();
+    match callee {
+      Expr::Atom(Atom::Function(function)) => {
+        if arguments.len() != function.parameters().len() {
+          return Err(WrongArity);
+        }
 
-    if parameters.len() != 1 {
-      return Err(WrongArity);
+        self.apply_function(function.clone(), arguments)
+      }
+      Expr::Atom(Atom::Native(native)) => native.call(self, arguments),
+      Expr::Atom(Atom::Macro(macr)) => {
+        let tail = arguments
+          .into_iter()
+          .rev()
+          .fold(List::Nil, |list, argument| List::cons(argument, list));
+
+        self.eval_call_macro(macr.clone(), &tail)
+      }
+      _ => Err(NotCallable),
     }
+  }
 
-    let parameter = self.as_symbol(parameters.get(0).unwrap().clone())?;
+  fn apply_function(
+    &mut self,
+    function: Function,
+    arguments: Vec<Expr>,
+  ) -> Result<Expr, EvalError> {
+    let mut frame = Frame::with_scope(
+      function.frame().clone(),
+      function.lambda().scope.clone(),
+    );
+    for (index, argument) in arguments.into_iter().enumerate() {
+      frame.set_at(index, argument);
+    }
 
-    Ok(Expr::Atom(Atom::Macro(Macro::new(parameter, body))))
+    self.eval_body(frame, function.body())
   }
 
-  pub fn eval_call_special_if(
+  pub fn eval_call_macro(
     &mut self,
-    tail: List,
+    macr: Macro,
+    tail: &List,
   ) -> Result<Expr, EvalError> {
+    let mut frame =
+      Frame::with_scope(macr.frame().clone(), macr.lambda().scope.clone());
+    frame.set_at(0, Expr::List(tail.clone()));
+
+    let expr = self.eval_body(frame, macr.body())?;
+
+    // The expansion is resolved in the scope of the call site, and is nested
+    // like a call so that macros expanding into themselves can't recurse
+    // forever.
+    self.nest(|evaluator| evaluator.eval_expr(expr))
+  }
+
+  // Counts an evaluation step, checking it against the budget.
+  fn consume(&mut self) -> Result<(), EvalError> {
     use EvalError::*;
 
-    if tail.len() != 3 {
-      return Err(WrongArity);
+    self.steps += 1;
+
+    if self.interrupt.swap(false, Ordering::Relaxed) {
+      return Err(Interrupted);
     }
 
-    let condition = self.eval_expr(tail.get(0).unwrap().clone())?;
+    // Reading the clock is slow, so the deadline is only checked every so
+    // often.
+    if let Some(deadline) = self.deadline {
+      if self.steps.is_multiple_of(1024) && Instant::now() >= deadline {
+        return Err(Timeout);
+      }
+    }
 
-    if condition.is_truthy() {
-      self.eval_expr(tail.get(1).unwrap().clone())
-    } else {
-      self.eval_expr(tail.get(2).unwrap().clone())
+    if let Some(fuel) = self.limits.fuel {
+      if self.steps > fuel {
+        return Err(FuelExhausted);
+      }
     }
+
+    if let Some(max_allocations) = self.limits.max_allocations {
+      if self.allocations() > max_allocations {
+        return Err(MemoryLimit);
+      }
+    }
+
+    Ok(())
   }
 
-  pub fn eval_call_special_quote(
+  // Evaluates the body of a function or macro in a new frame.
+  fn eval_body(
     &mut self,
-    tail: List,
+    frame: Frame,
+    body: &Code,
   ) -> Result<Expr, EvalError> {
+    self.nest(|evaluator| {
+      let original_frame = mem::replace(&mut evaluator.frame, frame);
+      let expr = evaluator.eval_code(body);
+      evaluator.frame = original_frame;
+
+      expr
+    })
+  }
+
+  // Evaluates one level deeper, checking the depth against the limit.
+  fn nest<F>(&mut self, f: F) -> Result<Expr, EvalError>
+  where
+    F: FnOnce(&mut Evaluator) -> Result<Expr, EvalError>,
+  {
     use EvalError::*;
 
-    if tail.len() != 1 {
-      return Err(WrongArity);
+    if let Some(max_depth) = self.limits.depth_limit() {
+      if self.depth >= max_depth {
+        return Err(StackOverflow);
+      }
     }
 
-    let expr = tail.get(0).unwrap().clone();
+    self.depth += 1;
+    let expr = f(self);
+    self.depth -= 1;
 
-    Ok(expr)
+    expr
+  }
+
+  pub fn eval_call_native(
+    &mut self,
+    native: Native,
+    arguments: &[Code],
+  ) -> Result<Expr, EvalError> {
+    let arguments = self.eval_arguments(arguments)?;
+
+    native.call(self, arguments)
+  }
+
+  pub fn eval_call_special(
+    &mut self,
+    special: Special,
+    tail: &List,
+  ) -> Result<Expr, EvalError> {
+    let expr =
+      Expr::List(List::cons(Expr::Atom(Atom::Special(special)), tail.clone()));
+
+    self.eval_expr(expr)
   }
 
-  pub fn eval_call_special_operator(
+  pub fn eval_operator(
     &mut self,
-    operator: Operator,
-    tail: List,
+    operator: &Operator,
+    left: &Code,
+    right: &Code,
   ) -> Result<Expr, EvalError> {
     use ast::Atom::*;
-    use ast::List::*;
-    use EvalError::*;
     use Expr::*;
     use Operator::*;
 
-    if tail.len() != 2 {
-      return Err(WrongArity);
-    }
-
-    let left = tail.get(0).unwrap().clone();
-    let right = tail.get(1).unwrap().clone();
-
-    let left = self.eval_expr(left)?;
-    let right = self.eval_expr(right)?;
+    let left = self.eval_code(left)?;
+    let right = self.eval_code(right)?;
 
     let result = match operator {
       Add => {
@@ -314,79 +911,61 @@ impl Evaluator {
       Gt => {
         let left = self.as_number(left)?;
         let right = self.as_number(right)?;
-        if left > right {
-          Atom(Symbol(SYMBOL_TRUE.clone()))
-        } else {
-          List(Nil)
-        }
+        Atom(Bool(left > right))
       }
       Lt => {
         let left = self.as_number(left)?;
         let right = self.as_number(right)?;
-        if left < right {
-          Atom(Symbol(SYMBOL_TRUE.clone()))
-        } else {
-          List(Nil)
-        }
-      }
-      Eq => {
-        if left == right {
-          Atom(Symbol(SYMBOL_TRUE.clone()))
-        } else {
-          List(Nil)
-        }
+        Atom(Bool(left < right))
       }
+      Eq => Atom(Bool(left == right)),
     };
 
     Ok(result)
   }
 
-  pub fn eval_atom(&mut self, atom: Atom) -> Result<Expr, EvalError> {
-    use Atom::*;
-
-    match atom {
-      Symbol(symbol) => self.eval_symbol(symbol),
-      atom => Ok(Expr::Atom(atom)),
-    }
-  }
-
-  pub fn eval_symbol(&mut self, symbol: Symbol) -> Result<Expr, EvalError> {
-    use EvalError::*;
-
-    match self.frame.get(&symbol) {
-      Some(expr) => Ok(expr.clone()),
-      None => Err(UndefinedSymbol(symbol)),
-    }
+  fn eval_arguments(
+    &mut self,
+    arguments: &[Code],
+  ) -> Result<Vec<Expr>, EvalError> {
+    arguments
+      .iter()
+      .map(|code| self.eval_code(code))
+      .collect::<Result<Vec<Expr>, EvalError>>()
   }
 
-  fn as_symbol(&mut self, expr: Expr) -> Result<Symbol, EvalError> {
+  fn as_number(&mut self, expr: Expr) -> Result<f64, EvalError> {
     use Atom::*;
     use EvalError::*;
 
     match expr {
-      Expr::Atom(Symbol(symbol)) => Ok(symbol),
+      Expr::Atom(Number(number)) => Ok(number),
       _ => Err(InvalidType),
     }
   }
+}
 
-  fn as_list(&mut self, expr: Expr) -> Result<List, EvalError> {
-    use EvalError::*;
-    use Expr::*;
+fn read_file(path: &Path) -> Result<(PathBuf, Expr), EvalError> {
+  let path = path.canonicalize()?;
+  let source = fs::read_to_string(&path)?;
+  let expr = read::read(&source)?;
 
-    match expr {
-      List(list) => Ok(list),
-      _ => Err(InvalidType),
-    }
-  }
+  Ok((path, expr))
+}
 
-  fn as_number(&mut self, expr: Expr) -> Result<f64, EvalError> {
-    use Atom::*;
-    use EvalError::*;
+impl Default for Evaluator {
+  fn default() -> Evaluator {
+    Evaluator::new()
+  }
+}
 
-    match expr {
-      Expr::Atom(Number(number)) => Ok(number),
-      _ => Err(InvalidType),
-    }
+impl Drop for Evaluator {
+  fn drop(&mut self) {
+    // Once the evaluator is gone its frames may only be referenced by cycles.
+    self.modules.clear();
+    self.frame = Frame::new();
+    self.base = Frame::new();
+    self.collect_garbage();
   }
 }
 
@@ -400,6 +979,57 @@ pub enum EvalError {
   UndefinedSymbol(Symbol),
   #[error("expression not callable")]
   NotCallable,
+  #[error("'{0}' must be at the top level")]
+  NotTopLevel(&'static str),
+  #[error("module '{0}' not found")]
+  ModuleNotFound(String),
+  #[error("circular import of module '{}'", .0.display())]
+  CircularImport(PathBuf),
+  #[error("map key is invalid")]
+  InvalidKey,
+  #[error("invalid format directive '{0}'")]
+  InvalidFormat(String),
+  #[error("port is closed")]
+  PortClosed,
+  #[error("index {0} is out of bounds")]
+  OutOfBounds(usize),
+  #[error("maximum call depth exceeded")]
+  StackOverflow,
+  #[error("fuel exhausted")]
+  FuelExhausted,
+  #[error("memory limit exceeded")]
+  MemoryLimit,
+  #[error("timed out")]
+  Timeout,
+  #[error("interrupted")]
+  Interrupted,
+  #[error("exited with status {0}")]
+  Exit(i32),
+  #[error("{0} access is denied")]
+  PermissionDenied(Capability),
+  #[error("{0}")]
+  Io(#[from] io::Error),
+  #[error("{0}")]
+  Read(#[from] ReadError),
   #[error("{0}")]
   Native(Box<dyn Error>),
 }
+
+impl EvalError {
+  // Returns whether the error can be caught by `try`. Errors that enforce
+  // limits or stop evaluation can't be, so that scripts can't ignore them,
+  // and neither can exiting.
+  pub fn is_catchable(&self) -> bool {
+    use EvalError::*;
+
+    !matches!(
+      self,
+      StackOverflow
+        | FuelExhausted
+        | MemoryLimit
+        | Timeout
+        | Interrupted
+        | Exit(_)
+    )
+  }
+}
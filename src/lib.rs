@@ -1,4 +1,5 @@
-use std::{fs, io};
+use std::io;
+use std::sync::atomic::Ordering;
 
 use rustyline::config::Configurer;
 use rustyline::error::ReadlineError;
@@ -9,43 +10,70 @@ use crate::ast::Expr;
 use crate::eval::{EvalError, Evaluator};
 use crate::read::ReadError;
 
-mod env;
-
 pub mod ast;
+pub mod check;
+pub mod env;
 pub mod eval;
+pub mod module;
 pub mod read;
+pub mod resolve;
 
 pub fn run() -> Result<(), RunError> {
   let args: Vec<String> = std::env::args().collect();
 
-  if let Some(path) = args.get(1) {
-    run_file(path)
-  } else {
-    run_repl()
+  // Arguments after the script's path are passed on to it.
+  let mut builder = Evaluator::builder().arguments(args.iter().skip(2));
+  if let Some(paths) = std::env::var_os("ZUKO_PATH") {
+    for path in std::env::split_paths(&paths) {
+      builder = builder.search_path(path);
+    }
+  }
+  let mut evaluator = builder.build()?;
+
+  match (args.get(1).map(String::as_str), args.get(2)) {
+    (Some("check"), Some(path)) => check_file(&evaluator, path),
+    (Some(path), _) => run_file(&mut evaluator, path),
+    (None, _) => run_repl(&mut evaluator),
   }
 }
 
-fn run_file(path: &str) -> Result<(), RunError> {
-  let source = fs::read_to_string(path)?;
+fn check_file(evaluator: &Evaluator, path: &str) -> Result<(), RunError> {
+  let warnings = evaluator.check_file(path)?;
+
+  for warning in warnings.iter() {
+    println!("{}:{}", path, warning);
+  }
+
+  if warnings.is_empty() {
+    Ok(())
+  } else {
+    Err(RunError::Check(warnings.len()))
+  }
+}
 
-  let expr = read::read(&source)?;
-  eval::eval(expr)?;
+fn run_file(evaluator: &mut Evaluator, path: &str) -> Result<(), RunError> {
+  evaluator.eval_file(path)?;
 
   Ok(())
 }
 
-fn run_repl() -> Result<(), RunError> {
+fn run_repl(evaluator: &mut Evaluator) -> Result<(), RunError> {
   println!("Zuko v1.0.0");
 
   let mut editor = Editor::<()>::new();
   editor.set_auto_add_history(true);
 
-  let mut evaluator = Evaluator::new();
+  // Ctrl-C while evaluating stops the evaluation instead of the REPL.
+  let interrupt = evaluator.interrupt_handle();
+  ctrlc::set_handler(move || interrupt.store(true, Ordering::Relaxed))?;
 
   loop {
     match editor.readline("> ") {
-      Ok(line) => match read_and_eval_line(&mut evaluator, &line) {
+      Ok(line) => match read_and_eval_line(evaluator, &line) {
         Ok(expr) => println!("{}", expr),
+        Err(RunError::Eval(EvalError::Exit(code))) => {
+          return Err(EvalError::Exit(code).into())
+        }
         Err(error) => println!("error: {}", error),
       },
       Err(ReadlineError::Interrupted) => break,
@@ -63,6 +91,8 @@ fn read_and_eval_line(
   line: &str,
 ) -> Result<Expr, RunError> {
   let expr = read::read(line)?;
+  evaluator.interrupt_handle().store(false, Ordering::Relaxed);
+  evaluator.reset_budget();
   let expr = evaluator.eval_expr(expr)?;
   Ok(expr)
 }
@@ -75,4 +105,8 @@ pub enum RunError {
   Read(#[from] ReadError),
   #[error("{0}")]
   Eval(#[from] EvalError),
+  #[error("{0}")]
+  Signal(#[from] ctrlc::Error),
+  #[error("found {0} warning(s)")]
+  Check(usize),
 }
@@ -1,10 +1,15 @@
 use std::process;
 
-use zuko::run;
+use zuko::eval::EvalError;
+use zuko::{run, RunError};
 
 fn main() {
-  if let Err(error) = run() {
-    println!("error: {}", error);
-    process::exit(1);
+  match run() {
+    Ok(()) => {}
+    Err(RunError::Eval(EvalError::Exit(code))) => process::exit(code),
+    Err(error) => {
+      println!("error: {}", error);
+      process::exit(1);
+    }
   }
 }
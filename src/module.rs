@@ -0,0 +1,186 @@
+use std::path::{Path, PathBuf};
+
+use crate::ast::{Atom, Expr, List, Special, Symbol};
+use crate::env::Frame;
+use crate::eval::EvalError;
+use crate::resolve;
+
+#[derive(Clone, Debug)]
+pub struct Module {
+  pub frame: Frame,
+  pub exports: Vec<Symbol>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+  Path(String),
+  Name(Symbol),
+}
+
+// A parsed `import` or `require` form.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Import {
+  pub target: Target,
+  pub prefix: Option<String>,
+  pub renames: Vec<(Symbol, Symbol)>,
+}
+
+impl Import {
+  pub fn parse(special: &Special, tail: &List) -> Result<Import, EvalError> {
+    use EvalError::*;
+
+    let mut terms = tail.clone().into_iter();
+
+    let target = match (special, terms.next()) {
+      (Special::Import, Some(Expr::Atom(Atom::String(path)))) => {
+        Target::Path(path)
+      }
+      (Special::Require, Some(Expr::Atom(Atom::Symbol(name)))) => {
+        Target::Name(name)
+      }
+      (_, Some(_)) => return Err(InvalidType),
+      (_, None) => return Err(WrongArity),
+    };
+
+    let mut import = Import {
+      target,
+      prefix: None,
+      renames: Vec::new(),
+    };
+
+    for option in terms {
+      let mut option = match option {
+        Expr::List(list) => list.into_iter(),
+        _ => return Err(InvalidType),
+      };
+
+      let name = match option.next() {
+        Some(Expr::Atom(Atom::Symbol(name))) => name,
+        _ => return Err(InvalidType),
+      };
+
+      match name.as_str() {
+        "prefix" => match (option.next(), option.next()) {
+          (Some(Expr::Atom(Atom::String(prefix))), None) => {
+            import.prefix = Some(prefix)
+          }
+          _ => return Err(InvalidType),
+        },
+        "rename" => {
+          for rename in option {
+            let rename = match rename {
+              Expr::List(list) => list,
+              _ => return Err(InvalidType),
+            };
+
+            match (rename.get(0), rename.get(1), rename.len()) {
+              (
+                Some(Expr::Atom(Atom::Symbol(from))),
+                Some(Expr::Atom(Atom::Symbol(to))),
+                2,
+              ) => import.renames.push((from.clone(), to.clone())),
+              _ => return Err(InvalidType),
+            }
+          }
+        }
+        _ => return Err(InvalidType),
+      }
+    }
+
+    Ok(import)
+  }
+
+  // Returns the name each export is bound to in the importing module.
+  pub fn bindings(&self, exports: &[Symbol]) -> Vec<(Symbol, Symbol)> {
+    exports
+      .iter()
+      .map(|export| {
+        let name = self
+          .renames
+          .iter()
+          .find(|(from, _)| from == export)
+          .map(|(_, to)| to.clone())
+          .unwrap_or_else(|| match &self.prefix {
+            Some(prefix) => Symbol::new(format!("{}{}", prefix, export)),
+            None => export.clone(),
+          });
+
+        (export.clone(), name)
+      })
+      .collect()
+  }
+
+  // Finds the file for the module, first relative to the importing file and
+  // then in each directory of the search path.
+  pub fn find(
+    &self,
+    from: Option<&Path>,
+    search_path: &[PathBuf],
+  ) -> Result<PathBuf, EvalError> {
+    use EvalError::*;
+
+    let name = match &self.target {
+      Target::Path(path) => PathBuf::from(path),
+      Target::Name(name) => PathBuf::from(format!("{}.zuko", name)),
+    };
+
+    let directory = from
+      .and_then(|from| from.parent())
+      .map(Path::to_path_buf)
+      .unwrap_or_default();
+
+    let path = std::iter::once(&directory)
+      .chain(search_path.iter())
+      .map(|directory| directory.join(&name))
+      .find(|path| path.is_file())
+      .ok_or_else(|| ModuleNotFound(name.display().to_string()))?;
+
+    Ok(path.canonicalize()?)
+  }
+}
+
+// Finds the imports at the top level of a program.
+pub fn collect_imports(expr: &Expr) -> Result<Vec<Import>, EvalError> {
+  let mut imports = Vec::new();
+
+  for (special, tail) in top_level_specials(expr) {
+    if let Special::Import | Special::Require = special {
+      imports.push(Import::parse(&special, &tail)?);
+    }
+  }
+
+  Ok(imports)
+}
+
+// Finds the symbols exported at the top level of a program.
+pub fn collect_exports(expr: &Expr) -> Result<Vec<Symbol>, EvalError> {
+  let mut exports = Vec::new();
+
+  for (special, tail) in top_level_specials(expr) {
+    if let Special::Export = special {
+      for expr in tail.into_iter() {
+        match expr {
+          Expr::Atom(Atom::Symbol(symbol)) => exports.push(symbol),
+          _ => return Err(EvalError::InvalidType),
+        }
+      }
+    }
+  }
+
+  Ok(exports)
+}
+
+fn top_level_specials(expr: &Expr) -> Vec<(Special, List)> {
+  resolve::top_level_forms(expr)
+    .into_iter()
+    .filter_map(|expr| match expr {
+      Expr::List(List::Cons(node)) => match &node.head {
+        Expr::Atom(Atom::Special(special)) => {
+          Some((special.clone(), node.tail.clone()))
+        }
+        _ => None,
+      },
+      _ => None,
+    })
+    .collect()
+}
@@ -2,7 +2,7 @@ use std::iter::{Iterator, Peekable};
 
 use thiserror::Error;
 
-use crate::ast::{self, Atom, Expr, List, Operator, Special, Symbol};
+use crate::ast::{self, Atom, Expr, List, Location, Operator, Special, Symbol};
 
 pub fn read(source: &str) -> Result<Expr, ReadError> {
   use List::*;
@@ -11,8 +11,10 @@ pub fn read(source: &str) -> Result<Expr, ReadError> {
 
   let mut exprs = vec![];
 
+  reader.skip_whitespace_or_comment();
   loop {
-    exprs.push(reader.read_expr()?);
+    let location = reader.location();
+    exprs.push((reader.read_expr()?, location));
     if reader.is_empty() {
       break;
     }
@@ -20,8 +22,8 @@ pub fn read(source: &str) -> Result<Expr, ReadError> {
 
   exprs.reverse();
   let mut list = Nil;
-  for expr in exprs.into_iter() {
-    list = List::cons(expr, list);
+  for (expr, location) in exprs.into_iter() {
+    list = List::cons_at(expr, list, location);
   }
 
   Ok(Expr::List(List::cons(
@@ -35,6 +37,9 @@ where
   I: Iterator<Item = char>,
 {
   source: Peekable<I>,
+  location: Location,
+  // Number of characters consumed so far.
+  offset: usize,
 }
 
 impl<I> Reader<I>
@@ -44,6 +49,8 @@ where
   pub fn new(source: I) -> Reader<I> {
     Reader {
       source: source.peekable(),
+      location: Location { line: 1, column: 1 },
+      offset: 0,
     }
   }
 
@@ -51,11 +58,37 @@ where
     self.source.peek().is_none()
   }
 
+  pub fn location(&self) -> Location {
+    self.location
+  }
+
+  pub fn offset(&self) -> usize {
+    self.offset
+  }
+
   pub fn read_expr(&mut self) -> Result<Expr, ReadError> {
-    use Expr::*;
-    use ReadError::*;
+    self.skip_whitespace_or_comment();
+    let expr = self.read_list_or_atom()?;
+    self.skip_whitespace_or_comment();
+
+    Ok(expr)
+  }
 
+  // Reads one expression without skipping what follows it, so that reading
+  // from a stream doesn't wait for more input. Returns None if there are no
+  // more expressions.
+  pub fn read_datum(&mut self) -> Result<Option<Expr>, ReadError> {
     self.skip_whitespace_or_comment();
+    if self.is_empty() {
+      return Ok(None);
+    }
+
+    self.read_list_or_atom().map(Some)
+  }
+
+  fn read_list_or_atom(&mut self) -> Result<Expr, ReadError> {
+    use Expr::*;
+    use ReadError::*;
 
     let expr = match self.source.peek() {
       Some('(') => List(self.read_list()?),
@@ -63,8 +96,6 @@ where
       None => return Err(UnexpectedEndOfInput),
     };
 
-    self.skip_whitespace_or_comment();
-
     Ok(expr)
   }
 
@@ -77,51 +108,117 @@ where
       Some(char) => return Err(UnexpectedChar(*char)),
       None => return Err(UnexpectedEndOfInput),
     }
-    self.source.next();
+    self.advance();
 
     // Catch empty lists
     self.skip_whitespace_or_comment();
     if let Some(')') = self.source.peek() {
-      self.source.next();
+      self.advance();
       return Ok(List::Nil);
     }
 
-    let mut exprs = vec![self.read_expr()?];
+    let location = self.location();
+    let mut exprs = vec![(self.read_expr()?, location)];
 
     loop {
       match self.source.peek() {
         Some(')') => {
-          self.source.next();
+          self.advance();
           break;
         }
         None => return Err(UnexpectedEndOfInput),
         _ => {}
       }
 
-      exprs.push(self.read_expr()?);
+      let location = self.location();
+      exprs.push((self.read_expr()?, location));
     }
 
     exprs.reverse();
     let mut list = Nil;
-    for expr in exprs.into_iter() {
-      list = List::cons(expr, list);
+    for (expr, location) in exprs.into_iter() {
+      list = List::cons_at(expr, list, location);
     }
 
     Ok(list)
   }
 
+  pub fn read_vector(&mut self) -> Result<Vec<Expr>, ReadError> {
+    use ReadError::*;
+
+    match self.source.peek() {
+      Some('[') => {}
+      Some(char) => return Err(UnexpectedChar(*char)),
+      None => return Err(UnexpectedEndOfInput),
+    }
+    self.advance();
+
+    let mut exprs = Vec::new();
+
+    loop {
+      self.skip_whitespace_or_comment();
+      match self.source.peek() {
+        Some(']') => {
+          self.advance();
+          break;
+        }
+        None => return Err(UnexpectedEndOfInput),
+        _ => {}
+      }
+
+      exprs.push(self.read_expr()?);
+    }
+
+    Ok(exprs)
+  }
+
+  pub fn read_map(&mut self) -> Result<ast::MapLiteral, ReadError> {
+    use ReadError::*;
+
+    match self.source.peek() {
+      Some('{') => {}
+      Some(char) => return Err(UnexpectedChar(*char)),
+      None => return Err(UnexpectedEndOfInput),
+    }
+    self.advance();
+
+    let mut entries = Vec::new();
+
+    loop {
+      self.skip_whitespace_or_comment();
+      match self.source.peek() {
+        Some('}') => {
+          self.advance();
+          break;
+        }
+        None => return Err(UnexpectedEndOfInput),
+        _ => {}
+      }
+
+      let key = self.read_expr()?;
+      if let Some('}') = self.source.peek() {
+        return Err(MissingValue);
+      }
+      let value = self.read_expr()?;
+
+      entries.push((key, value));
+    }
+
+    Ok(ast::MapLiteral::new(entries))
+  }
+
   pub fn read_atom(&mut self) -> Result<Atom, ReadError> {
-    use ast::Special::Operator;
     use Atom::*;
     use ReadError::*;
 
     let atom = match self.source.peek() {
       Some('"') => String(self.read_string()?),
-      Some(char) if char.is_digit(10) => Number(self.read_number()?),
-      Some(char) if is_operator(*char) => {
-        Special(Operator(self.read_operator()?))
-      }
-      Some(char) if is_symbol(*char) => self.read_symbol_or_special()?,
+      Some('[') => Vector(ast::Vector::new(self.read_vector()?)),
+      Some('{') => MapLiteral(self.read_map()?),
+      Some('#') => self.read_hash()?,
+      Some('|') => Symbol(self.read_escaped_symbol()?),
+      Some(':') => Keyword(self.read_keyword()?),
+      Some(char) if is_symbol(*char) => self.read_word()?,
       Some(char) => return Err(UnexpectedChar(*char)),
       None => return Err(UnexpectedEndOfInput),
     };
@@ -129,105 +226,124 @@ where
     Ok(atom)
   }
 
-  pub fn read_number(&mut self) -> Result<f64, ReadError> {
+  // Reads a number, operator, symbol or special form. These are all made up of
+  // the same characters, so they are told apart once the whole word is read.
+  // Words starting with a digit, or a sign or point and then a digit, are
+  // numbers, and words of a single operator character are operators.
+  pub fn read_word(&mut self) -> Result<Atom, ReadError> {
     use ReadError::*;
 
-    let mut buf = Vec::new();
-    let mut has_decimal = false;
+    let mut word = String::new();
 
     loop {
       match self.source.peek() {
-        Some('.') if !has_decimal => has_decimal = true,
-        Some(char) if char.is_digit(10) => {}
-        Some(')') => break,
-        Some(char) if char.is_whitespace() => break,
+        Some(char) if is_symbol(*char) => {}
+        Some(char) if is_delimiter(*char) => break,
         Some(char) => return Err(UnexpectedChar(*char)),
         None => break,
       }
-      let char = self.source.next().unwrap();
 
-      buf.push(char);
+      word.push(self.advance().unwrap());
     }
 
-    let buf: String = buf.into_iter().collect();
-    let number = buf.parse().unwrap();
-
-    Ok(number)
-  }
-
-  pub fn read_symbol_or_special(&mut self) -> Result<Atom, ReadError> {
-    use Special::*;
-
-    let symbol = self.read_symbol()?;
+    if let Some(operator) = parse_operator(&word) {
+      return Ok(Atom::Special(Special::Operator(operator)));
+    }
 
-    let special = match symbol.as_str() {
-      "begin" => Begin,
-      "define" => Define,
-      "function" => Function,
-      "macro" => Macro,
-      "if" => If,
-      "quote" => Quote,
-      _ => return Ok(Atom::Symbol(symbol)),
-    };
+    if is_number(&word) {
+      return parse_number(&word).map(Atom::Number);
+    }
 
-    Ok(Atom::Special(special))
+    Ok(parse_symbol_or_special(word))
   }
 
-  pub fn read_symbol(&mut self) -> Result<Symbol, ReadError> {
+  // Reads a keyword, whose name is written like a symbol after the colon.
+  pub fn read_keyword(&mut self) -> Result<ast::Keyword, ReadError> {
     use ReadError::*;
 
-    let mut buf = Vec::new();
-    let mut should_break = false;
-    let mut prev_punct_dist = 0;
+    match self.source.peek() {
+      Some(':') => {}
+      Some(char) => return Err(UnexpectedChar(*char)),
+      None => return Err(UnexpectedEndOfInput),
+    }
+    self.advance();
+
+    if let Some('|') = self.source.peek() {
+      let name = self.read_escaped_symbol()?;
+      return Ok(ast::Keyword::new(name.as_str()));
+    }
+
+    let mut name = String::new();
 
     loop {
       match self.source.peek() {
-        Some(')') => break,
-        Some(char) if char.is_whitespace() => break,
-        Some(char) if should_break => return Err(UnexpectedChar(*char)),
-        Some(char) if char.is_alphabetic() && char.is_lowercase() => {}
-        Some('-') | Some('/') if prev_punct_dist > 0 => {
-          prev_punct_dist = -1;
-        }
-        Some('?') => should_break = true,
+        Some(char) if is_symbol(*char) => {}
+        Some(char) if is_delimiter(*char) && !name.is_empty() => break,
         Some(char) => return Err(UnexpectedChar(*char)),
+        None if name.is_empty() => return Err(UnexpectedEndOfInput),
         None => break,
       }
-      let char = self.source.next().unwrap();
 
-      buf.push(char);
-      prev_punct_dist += 1;
+      name.push(self.advance().unwrap());
     }
 
-    let last_char = buf.last().cloned();
-    if let Some('-') | Some('/') = last_char {
-      return Err(UnexpectedChar(last_char.unwrap()));
+    Ok(ast::Keyword::new(name))
+  }
+
+  // Reads a symbol written between bars, like `|hello world|`, which can
+  // contain any character. Bars and backslashes inside are escaped.
+  pub fn read_escaped_symbol(&mut self) -> Result<Symbol, ReadError> {
+    use ReadError::*;
+
+    match self.source.peek() {
+      Some('|') => {}
+      Some(char) => return Err(UnexpectedChar(*char)),
+      None => return Err(UnexpectedEndOfInput),
     }
+    self.advance();
 
-    let buf: String = buf.into_iter().collect();
+    let mut buf = String::new();
+
+    loop {
+      match self.advance() {
+        Some('|') => break,
+        Some('\\') if self.source.peek() == Some(&'|') => {
+          buf.push(self.advance().unwrap());
+        }
+        Some('\\') => buf.push(self.read_escape()?),
+        Some(char) => buf.push(char),
+        None => return Err(UnexpectedEndOfInput),
+      }
+    }
 
     Ok(Symbol::new(buf))
   }
 
-  pub fn read_operator(&mut self) -> Result<Operator, ReadError> {
-    use Operator::*;
+  // Reads the atoms written with a leading `#`.
+  pub fn read_hash(&mut self) -> Result<Atom, ReadError> {
     use ReadError::*;
 
-    let operator = match self.source.peek() {
-      Some('+') => Add,
-      Some('-') => Sub,
-      Some('*') => Mul,
-      Some('/') => Div,
-      Some('%') => Mod,
-      Some('>') => Gt,
-      Some('<') => Lt,
-      Some('=') => Eq,
+    match self.source.peek() {
+      Some('#') => {}
+      Some(char) => return Err(UnexpectedChar(*char)),
+      None => return Err(UnexpectedEndOfInput),
+    }
+    self.advance();
+
+    let atom = match self.source.peek() {
+      Some('r') => return Ok(Atom::String(self.read_raw_string()?)),
+      Some('\\') => return Ok(Atom::Char(self.read_char()?)),
+      Some('t') => Atom::Bool(true),
+      Some('f') => Atom::Bool(false),
       Some(char) => return Err(UnexpectedChar(*char)),
       None => return Err(UnexpectedEndOfInput),
     };
-    self.source.next();
+    self.advance();
 
-    Ok(operator)
+    match self.source.peek() {
+      Some(char) if !is_delimiter(*char) => Err(UnexpectedChar(*char)),
+      _ => Ok(atom),
+    }
   }
 
   pub fn read_string(&mut self) -> Result<String, ReadError> {
@@ -238,17 +354,22 @@ where
       Some(char) => return Err(UnexpectedChar(*char)),
       None => return Err(UnexpectedEndOfInput),
     }
-    self.source.next();
+    self.advance();
 
     let mut buf = Vec::new();
 
     loop {
       match self.source.peek() {
         Some('"') => break,
+        Some('\\') => {
+          self.advance();
+          buf.push(self.read_escape()?);
+          continue;
+        }
         Some(_) => {}
         None => return Err(UnexpectedEndOfInput),
       }
-      let char = self.source.next().unwrap();
+      let char = self.advance().unwrap();
 
       buf.push(char);
     }
@@ -256,7 +377,152 @@ where
     let buf: String = buf.into_iter().collect();
 
     // Get rid of final quote.
-    self.source.next();
+    self.advance();
+
+    Ok(buf)
+  }
+
+  // Reads the rest of an escape sequence, after the backslash.
+  fn read_escape(&mut self) -> Result<char, ReadError> {
+    use ReadError::*;
+
+    let char = match self.advance() {
+      Some('n') => '\n',
+      Some('t') => '\t',
+      Some('r') => '\r',
+      Some('0') => '\0',
+      Some('\\') => '\\',
+      Some('"') => '"',
+      Some('u') => return self.read_unicode_escape(),
+      Some(char) => return Err(InvalidEscape(char)),
+      None => return Err(UnexpectedEndOfInput),
+    };
+
+    Ok(char)
+  }
+
+  // Reads an escape like `\u{1f600}`, after the `u`.
+  fn read_unicode_escape(&mut self) -> Result<char, ReadError> {
+    use ReadError::*;
+
+    match self.advance() {
+      Some('{') => {}
+      Some(char) => return Err(UnexpectedChar(char)),
+      None => return Err(UnexpectedEndOfInput),
+    }
+
+    let mut digits = String::new();
+
+    loop {
+      match self.advance() {
+        Some('}') => break,
+        Some(char) if char.is_ascii_hexdigit() && digits.len() < 6 => {
+          digits.push(char)
+        }
+        Some(char) => return Err(UnexpectedChar(char)),
+        None => return Err(UnexpectedEndOfInput),
+      }
+    }
+
+    u32::from_str_radix(&digits, 16)
+      .ok()
+      .and_then(std::char::from_u32)
+      .ok_or(InvalidUnicode(digits))
+  }
+
+  // Reads a character like `#\a` after the `#`. Invisible characters are
+  // written with names like `#\space`, or in hex like `#\x7f`.
+  fn read_char(&mut self) -> Result<char, ReadError> {
+    use ReadError::*;
+
+    match self.source.peek() {
+      Some('\\') => {}
+      Some(char) => return Err(UnexpectedChar(*char)),
+      None => return Err(UnexpectedEndOfInput),
+    }
+    self.advance();
+
+    // The first character is taken even if it's a delimiter, like in `#\(`.
+    let mut name = match self.advance() {
+      Some(char) => char.to_string(),
+      None => return Err(UnexpectedEndOfInput),
+    };
+
+    loop {
+      match self.source.peek() {
+        Some(char) if is_delimiter(*char) => break,
+        Some(char) => name.push(*char),
+        None => break,
+      }
+      self.advance();
+    }
+
+    let mut chars = name.chars();
+    if let (Some(char), None) = (chars.next(), chars.next()) {
+      return Ok(char);
+    }
+
+    let char = match name.as_str() {
+      "space" => ' ',
+      "newline" => '\n',
+      "tab" => '\t',
+      "return" => '\r',
+      "nul" => '\0',
+      name => name
+        .strip_prefix('x')
+        .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+        .and_then(std::char::from_u32)
+        .ok_or_else(|| InvalidChar(name.to_string()))?,
+    };
+
+    Ok(char)
+  }
+
+  // Reads a raw string like `#r"..."` after the `#`. Raw strings have no
+  // escapes, and can be delimited by any number of `#` to contain quotes, like
+  // `#r#"say "hi""#`.
+  fn read_raw_string(&mut self) -> Result<String, ReadError> {
+    use ReadError::*;
+
+    match self.source.peek() {
+      Some('r') => {}
+      Some(char) => return Err(UnexpectedChar(*char)),
+      None => return Err(UnexpectedEndOfInput),
+    }
+    self.advance();
+
+    let mut hashes = 0;
+    while let Some('#') = self.source.peek() {
+      self.advance();
+      hashes += 1;
+    }
+
+    match self.advance() {
+      Some('"') => {}
+      Some(char) => return Err(UnexpectedChar(char)),
+      None => return Err(UnexpectedEndOfInput),
+    }
+
+    let mut buf = String::new();
+
+    loop {
+      match self.advance() {
+        Some('"') => {
+          let mut count = 0;
+          while count < hashes && self.source.peek() == Some(&'#') {
+            self.advance();
+            count += 1;
+          }
+          if count == hashes {
+            break;
+          }
+          buf.push('"');
+          buf.extend(std::iter::repeat_n('#', count));
+        }
+        Some(char) => buf.push(char),
+        None => return Err(UnexpectedEndOfInput),
+      }
+    }
 
     Ok(buf)
   }
@@ -264,12 +530,16 @@ where
   pub fn skip_whitespace_or_comment(&mut self) {
     loop {
       match self.source.peek() {
-        Some(';') => self.skip_comment(),
+        // The comment's newline is skipped along with it.
+        Some(';') => {
+          self.skip_comment();
+          continue;
+        }
         Some(char) if !char.is_whitespace() => break,
         None => break,
         _ => {}
       }
-      self.source.next();
+      self.advance();
     }
   }
 
@@ -279,7 +549,7 @@ where
       Some(_) => return,
       None => return,
     };
-    self.source.next();
+    self.advance();
 
     loop {
       match self.source.peek() {
@@ -287,11 +557,30 @@ where
         Some(_) => {}
         None => return,
       }
-      self.source.next();
+      self.advance();
     }
 
     // Get rid of final newline.
-    self.source.next();
+    self.advance();
+  }
+
+  fn advance(&mut self) -> Option<char> {
+    let char = self.source.next();
+
+    if char.is_some() {
+      self.offset += 1;
+    }
+
+    match char {
+      Some('\n') => {
+        self.location.line += 1;
+        self.location.column = 1;
+      }
+      Some(_) => self.location.column += 1,
+      None => {}
+    }
+
+    char
   }
 }
 
@@ -301,15 +590,121 @@ pub enum ReadError {
   UnexpectedEndOfInput,
   #[error("unexpected char '{0}'")]
   UnexpectedChar(char),
+  #[error("invalid escape '\\{0}'")]
+  InvalidEscape(char),
+  #[error("invalid unicode escape '{0}'")]
+  InvalidUnicode(String),
+  #[error("invalid character '#\\{0}'")]
+  InvalidChar(String),
+  #[error("invalid number '{0}'")]
+  InvalidNumber(String),
+  #[error("map key is missing a value")]
+  MissingValue,
 }
 
+// Symbols are made up of letters, digits and some punctuation.
 fn is_symbol(char: char) -> bool {
-  char.is_alphabetic() && char.is_lowercase()
+  char.is_alphanumeric() || "!$%&*/<=>?^_~+-.".contains(char)
 }
 
-fn is_operator(char: char) -> bool {
-  match char {
-    '+' | '-' | '*' | '/' | '%' | '>' | '<' | '=' => true,
-    _ => false,
+fn is_delimiter(char: char) -> bool {
+  char.is_whitespace()
+    || matches!(char, '(' | ')' | '[' | ']' | '{' | '}' | '"' | ';')
+}
+
+fn is_number(word: &str) -> bool {
+  let mut chars = word.chars().skip_while(|char| matches!(char, '+' | '-'));
+  match chars.next() {
+    Some('.') => matches!(chars.next(), Some(char) if char.is_ascii_digit()),
+    Some(char) => char.is_ascii_digit(),
+    None => false,
+  }
+}
+
+// Numbers have an optional sign, then digits with an optional decimal point.
+fn parse_number(word: &str) -> Result<f64, ReadError> {
+  let digits = word.strip_prefix(['+', '-']).unwrap_or(word);
+
+  let is_valid = digits.chars().any(|char| char.is_ascii_digit())
+    && digits.chars().filter(|char| *char == '.').count() <= 1
+    && digits
+      .chars()
+      .all(|char| char.is_ascii_digit() || char == '.');
+
+  if !is_valid {
+    return Err(ReadError::InvalidNumber(word.to_string()));
   }
+
+  Ok(word.parse().unwrap())
+}
+
+// Parses a whole string as a number, the same way the reader does.
+pub(crate) fn string_to_number(string: &str) -> Option<f64> {
+  if is_number(string) {
+    parse_number(string).ok()
+  } else {
+    None
+  }
+}
+
+fn parse_operator(word: &str) -> Option<Operator> {
+  use Operator::*;
+
+  let operator = match word {
+    "+" => Add,
+    "-" => Sub,
+    "*" => Mul,
+    "/" => Div,
+    "%" => Mod,
+    ">" => Gt,
+    "<" => Lt,
+    "=" => Eq,
+    _ => return None,
+  };
+
+  Some(operator)
+}
+
+fn parse_symbol_or_special(word: String) -> Atom {
+  use Special::*;
+
+  let special = match word.as_str() {
+    "begin" => Begin,
+    "define" => Define,
+    "function" => Function,
+    "macro" => Macro,
+    "if" => If,
+    "quote" => Quote,
+    "import" => Import,
+    "require" => Require,
+    "export" => Export,
+    "true" => return Atom::Bool(true),
+    "false" => return Atom::Bool(false),
+    _ => return Atom::Symbol(Symbol::new(word)),
+  };
+
+  Atom::Special(special)
+}
+
+// Returns the name of a character that is written by name.
+pub(crate) fn char_name(char: char) -> Option<&'static str> {
+  let name = match char {
+    ' ' => "space",
+    '\n' => "newline",
+    '\t' => "tab",
+    '\r' => "return",
+    '\0' => "nul",
+    _ => return None,
+  };
+
+  Some(name)
+}
+
+// Returns whether a symbol reads back as itself without bars.
+pub(crate) fn is_plain_symbol(symbol: &str) -> bool {
+  !symbol.is_empty()
+    && symbol.chars().all(is_symbol)
+    && parse_operator(symbol).is_none()
+    && !is_number(symbol)
+    && matches!(parse_symbol_or_special(symbol.to_string()), Atom::Symbol(_))
 }
@@ -0,0 +1,534 @@
+use std::cell::OnceCell;
+use std::rc::Rc;
+
+use crate::ast::{
+  Atom, Expr, FromExpr, IntoExpr, Key, List, Map, MapLiteral, Node, Operator,
+  Special, Symbol, Vector,
+};
+use crate::env::Scope;
+use crate::eval::EvalError;
+
+#[derive(Clone, Debug)]
+pub enum Code {
+  Constant(Expr),
+  Variable(Address),
+  Define(usize, Box<Code>),
+  Begin(Vec<Code>),
+  // Creates a new vector from its items each time it is evaluated.
+  Vector(Vec<Code>),
+  // Likewise for maps, whose keys are evaluated too.
+  Map(Vec<(Code, Code)>),
+  Function(Rc<Lambda>),
+  Macro(Rc<Lambda>),
+  If(Box<Code>, Box<Code>, Box<Code>),
+  Operator(Operator, Box<Code>, Box<Code>),
+  Call(Box<Call>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Address {
+  pub depth: usize,
+  pub index: usize,
+  pub symbol: Symbol,
+}
+
+#[derive(Debug)]
+pub struct Lambda {
+  pub parameters: Vec<Symbol>,
+  pub scope: Scope,
+  pub body: Code,
+}
+
+#[derive(Clone, Debug)]
+pub struct Call {
+  pub head: Code,
+  // Resolved the first time the call runs, once the head is known not to be a
+  // macro, whose arguments needn't be valid code.
+  pub arguments: OnceCell<Vec<Code>>,
+  // Kept around for macros and specials, which need the raw terms.
+  pub tail: List,
+}
+
+pub struct Resolver {
+  scope: Scope,
+  globals: Scope,
+  // Distance from the current scope to the global scope.
+  depth: usize,
+  top_level: bool,
+  referenced: Vec<Symbol>,
+  defined: Vec<Symbol>,
+}
+
+impl Resolver {
+  pub fn new(scope: Scope) -> Resolver {
+    let mut globals = scope.clone();
+    let mut depth = 0;
+    while !globals.is_global() {
+      globals = globals.parent().unwrap();
+      depth += 1;
+    }
+
+    Resolver {
+      scope,
+      globals,
+      depth,
+      top_level: false,
+      referenced: Vec::new(),
+      defined: Vec::new(),
+    }
+  }
+
+  // Returns the globals referenced since the last call that aren't defined by
+  // any of the declared code.
+  pub fn take_free(&mut self) -> Vec<Symbol> {
+    let defined = &self.defined;
+
+    self
+      .referenced
+      .drain(..)
+      .filter(|symbol| !defined.contains(symbol))
+      .collect()
+  }
+
+  // Allocates slots for the symbols defined by the code, so that it can be
+  // referenced before it is resolved.
+  pub fn declare(&mut self, expr: &Expr) {
+    let mut defined = Vec::new();
+    collect_defines(expr, &mut defined);
+
+    for symbol in defined.into_iter() {
+      if self.depth == 0 && !self.defined.contains(&symbol) {
+        self.defined.push(symbol.clone());
+      }
+      self.scope.define(symbol);
+    }
+  }
+
+  pub fn resolve(&mut self, expr: &Expr) -> Result<Code, EvalError> {
+    self.declare(expr);
+
+    self.top_level = self.depth == 0;
+    self.resolve_expr(expr)
+  }
+
+  // Resolves the arguments of a call, in the scope the call was resolved in.
+  pub fn resolve_arguments(
+    &mut self,
+    tail: &List,
+  ) -> Result<Vec<Code>, EvalError> {
+    tail
+      .clone()
+      .into_iter()
+      .map(|expr| self.resolve_expr(&expr))
+      .collect()
+  }
+
+  fn resolve_expr(&mut self, expr: &Expr) -> Result<Code, EvalError> {
+    match expr {
+      Expr::List(list) => self.resolve_list(list),
+      Expr::Atom(Atom::Symbol(symbol)) => self.resolve_symbol(symbol),
+      Expr::Atom(Atom::Vector(vector)) => self.resolve_vector(vector),
+      Expr::Atom(Atom::Map(map)) => self.resolve_map(map),
+      Expr::Atom(Atom::MapLiteral(literal)) => {
+        self.resolve_map_literal(literal)
+      }
+      expr => Ok(Code::Constant(expr.clone())),
+    }
+  }
+
+  fn resolve_symbol(&mut self, symbol: &Symbol) -> Result<Code, EvalError> {
+    let (depth, index) = match self.scope.resolve(symbol) {
+      Some(address) => address,
+      None => (self.depth, self.globals.define(symbol.clone())),
+    };
+
+    if depth == self.depth && !self.referenced.contains(symbol) {
+      self.referenced.push(symbol.clone());
+    }
+
+    Ok(Code::Variable(Address {
+      depth,
+      index,
+      symbol: symbol.clone(),
+    }))
+  }
+
+  fn resolve_vector(&mut self, vector: &Vector) -> Result<Code, EvalError> {
+    let codes = vector
+      .to_vec()
+      .iter()
+      .map(|expr| self.resolve_expr(expr))
+      .collect::<Result<Vec<Code>, EvalError>>()?;
+
+    Ok(Code::Vector(codes))
+  }
+
+  fn resolve_map(&mut self, map: &Map) -> Result<Code, EvalError> {
+    let entries = map
+      .entries()
+      .iter()
+      .map(|(key, value)| {
+        Ok((
+          self.resolve_expr(&key.clone().into_expr())?,
+          self.resolve_expr(value)?,
+        ))
+      })
+      .collect::<Result<Vec<(Code, Code)>, EvalError>>()?;
+
+    Ok(Code::Map(entries))
+  }
+
+  // Entries are evaluated in the order they are written.
+  fn resolve_map_literal(
+    &mut self,
+    literal: &MapLiteral,
+  ) -> Result<Code, EvalError> {
+    let entries = literal
+      .entries()
+      .iter()
+      .map(|(key, value)| {
+        Ok((self.resolve_expr(key)?, self.resolve_expr(value)?))
+      })
+      .collect::<Result<Vec<(Code, Code)>, EvalError>>()?;
+
+    Ok(Code::Map(entries))
+  }
+
+  fn resolve_list(&mut self, list: &List) -> Result<Code, EvalError> {
+    use List::*;
+
+    let node = match list {
+      Cons(node) => node.as_ref(),
+      Nil => return Ok(Code::Constant(Expr::List(Nil))),
+    };
+
+    let top_level = std::mem::replace(&mut self.top_level, false);
+    let code = self.resolve_cons(node, top_level);
+    self.top_level = top_level;
+
+    code
+  }
+
+  fn resolve_cons(
+    &mut self,
+    node: &Node,
+    top_level: bool,
+  ) -> Result<Code, EvalError> {
+    if let Expr::Atom(Atom::Special(special)) = &node.head {
+      return self.resolve_special(special, &node.tail, top_level);
+    }
+
+    let head = self.resolve_expr(&node.head)?;
+
+    Ok(Code::Call(Box::new(Call {
+      head,
+      arguments: OnceCell::new(),
+      tail: node.tail.clone(),
+    })))
+  }
+
+  fn resolve_special(
+    &mut self,
+    special: &Special,
+    tail: &List,
+    top_level: bool,
+  ) -> Result<Code, EvalError> {
+    use Special::*;
+
+    match special {
+      Begin => self.resolve_special_begin(tail, top_level),
+      Define => self.resolve_special_define(tail),
+      Function => self.resolve_special_function(tail),
+      Macro => self.resolve_special_macro(tail),
+      If => self.resolve_special_if(tail),
+      Quote => self.resolve_special_quote(tail),
+      Operator(operator) => self.resolve_special_operator(operator, tail),
+      Import => self.resolve_special_module("import", top_level),
+      Require => self.resolve_special_module("require", top_level),
+      Export => self.resolve_special_module("export", top_level),
+    }
+  }
+
+  fn resolve_special_begin(
+    &mut self,
+    tail: &List,
+    top_level: bool,
+  ) -> Result<Code, EvalError> {
+    use EvalError::*;
+
+    if tail.is_empty() {
+      return Err(WrongArity);
+    }
+
+    self.top_level = top_level;
+
+    let codes = tail
+      .clone()
+      .into_iter()
+      .map(|expr| self.resolve_expr(&expr))
+      .collect::<Result<Vec<Code>, EvalError>>()?;
+
+    Ok(Code::Begin(codes))
+  }
+
+  fn resolve_special_define(&mut self, tail: &List) -> Result<Code, EvalError> {
+    use EvalError::*;
+
+    if tail.len() != 2 {
+      return Err(WrongArity);
+    }
+
+    let symbol = as_symbol(tail.get(0).unwrap())?;
+    let index = self.scope.define(symbol);
+    let code = self.resolve_expr(tail.get(1).unwrap())?;
+
+    Ok(Code::Define(index, Box::new(code)))
+  }
+
+  fn resolve_special_function(
+    &mut self,
+    tail: &List,
+  ) -> Result<Code, EvalError> {
+    use EvalError::*;
+
+    if tail.len() != 2 {
+      return Err(WrongArity);
+    }
+
+    let parameters = as_list(tail.get(0).unwrap())?
+      .into_iter()
+      .map(|expr| as_symbol(&expr))
+      .collect::<Result<Vec<Symbol>, EvalError>>()?;
+
+    let lambda = self.resolve_lambda(parameters, tail.get(1).unwrap())?;
+
+    Ok(Code::Function(Rc::new(lambda)))
+  }
+
+  fn resolve_special_macro(&mut self, tail: &List) -> Result<Code, EvalError> {
+    use EvalError::*;
+
+    if tail.len() != 2 {
+      return Err(WrongArity);
+    }
+
+    let parameters = as_list(tail.get(0).unwrap())?;
+
+    if parameters.len() != 1 {
+      return Err(WrongArity);
+    }
+
+    let parameter = as_symbol(parameters.get(0).unwrap())?;
+
+    let lambda = self.resolve_lambda(vec![parameter], tail.get(1).unwrap())?;
+
+    Ok(Code::Macro(Rc::new(lambda)))
+  }
+
+  fn resolve_special_if(&mut self, tail: &List) -> Result<Code, EvalError> {
+    use EvalError::*;
+
+    if tail.len() != 3 {
+      return Err(WrongArity);
+    }
+
+    let condition = self.resolve_expr(tail.get(0).unwrap())?;
+    let consequent = self.resolve_expr(tail.get(1).unwrap())?;
+    let alternative = self.resolve_expr(tail.get(2).unwrap())?;
+
+    Ok(Code::If(
+      Box::new(condition),
+      Box::new(consequent),
+      Box::new(alternative),
+    ))
+  }
+
+  fn resolve_special_quote(&mut self, tail: &List) -> Result<Code, EvalError> {
+    use EvalError::*;
+
+    if tail.len() != 1 {
+      return Err(WrongArity);
+    }
+
+    Ok(Code::Constant(quote(tail.get(0).unwrap())?))
+  }
+
+  fn resolve_special_operator(
+    &mut self,
+    operator: &Operator,
+    tail: &List,
+  ) -> Result<Code, EvalError> {
+    use EvalError::*;
+
+    if tail.len() != 2 {
+      return Err(WrongArity);
+    }
+
+    let left = self.resolve_expr(tail.get(0).unwrap())?;
+    let right = self.resolve_expr(tail.get(1).unwrap())?;
+
+    Ok(Code::Operator(
+      operator.clone(),
+      Box::new(left),
+      Box::new(right),
+    ))
+  }
+
+  // Modules are loaded before the program is resolved, so there is nothing
+  // left to do besides making sure they're at the top level.
+  fn resolve_special_module(
+    &mut self,
+    name: &'static str,
+    top_level: bool,
+  ) -> Result<Code, EvalError> {
+    use EvalError::*;
+
+    if !top_level {
+      return Err(NotTopLevel(name));
+    }
+
+    Ok(Code::Constant(Expr::List(List::Nil)))
+  }
+
+  fn resolve_lambda(
+    &mut self,
+    parameters: Vec<Symbol>,
+    body: &Expr,
+  ) -> Result<Lambda, EvalError> {
+    let scope = Scope::with_parent(self.scope.clone());
+    for parameter in parameters.iter() {
+      scope.define(parameter.clone());
+    }
+
+    let mut defined = Vec::new();
+    collect_defines(body, &mut defined);
+    for symbol in defined.into_iter() {
+      scope.define(symbol);
+    }
+
+    let parent = std::mem::replace(&mut self.scope, scope.clone());
+    self.depth += 1;
+    let body = self.resolve_expr(body);
+    self.depth -= 1;
+    self.scope = parent;
+
+    Ok(Lambda {
+      parameters,
+      scope,
+      body: body?,
+    })
+  }
+}
+
+// Splits a program into the forms at its top level.
+pub fn top_level_forms(expr: &Expr) -> Vec<Expr> {
+  if let Expr::List(List::Cons(node)) = expr {
+    if let (Expr::Atom(Atom::Special(Special::Begin)), false) =
+      (&node.head, node.tail.is_empty())
+    {
+      return node
+        .tail
+        .clone()
+        .into_iter()
+        .flat_map(|expr| top_level_forms(&expr))
+        .collect();
+    }
+  }
+
+  vec![expr.clone()]
+}
+
+// Finds the symbols defined directly in the body of a function, so that they
+// are allocated slots before any references to them are resolved.
+fn collect_defines(expr: &Expr, defined: &mut Vec<Symbol>) {
+  use List::*;
+  use Special::*;
+
+  let list = match expr {
+    Expr::List(list) => list,
+    _ => return,
+  };
+
+  let node = match list {
+    Cons(node) => node.as_ref(),
+    Nil => return,
+  };
+
+  match &node.head {
+    Expr::Atom(Atom::Special(Function))
+    | Expr::Atom(Atom::Special(Macro))
+    | Expr::Atom(Atom::Special(Quote)) => return,
+    Expr::Atom(Atom::Special(Define)) => {
+      if let Some(Expr::Atom(Atom::Symbol(symbol))) = node.tail.get(0) {
+        defined.push(symbol.clone());
+      }
+    }
+    _ => {}
+  }
+
+  for expr in list.clone().into_iter() {
+    collect_defines(&expr, defined);
+  }
+}
+
+// Turns the map literals in quoted code into maps, as quoted code is data.
+fn quote(expr: &Expr) -> Result<Expr, EvalError> {
+  if !contains_map_literal(expr) {
+    return Ok(expr.clone());
+  }
+
+  match expr {
+    Expr::Atom(Atom::MapLiteral(literal)) => {
+      let mut map = Map::new();
+      for (key, value) in literal.entries().iter() {
+        map = map.insert(Key::from_expr(quote(key)?)?, quote(value)?);
+      }
+      Ok(map.into_expr())
+    }
+    Expr::Atom(Atom::Vector(vector)) => {
+      let items = vector
+        .to_vec()
+        .iter()
+        .map(quote)
+        .collect::<Result<Vec<Expr>, EvalError>>()?;
+      Ok(Vector::new(items).into_expr())
+    }
+    Expr::List(list) => Ok(
+      list
+        .clone()
+        .into_iter()
+        .map(|expr| quote(&expr))
+        .collect::<Result<Vec<Expr>, EvalError>>()?
+        .into_expr(),
+    ),
+    expr => Ok(expr.clone()),
+  }
+}
+
+fn contains_map_literal(expr: &Expr) -> bool {
+  match expr {
+    Expr::Atom(Atom::MapLiteral(_)) => true,
+    Expr::Atom(Atom::Vector(vector)) => {
+      vector.to_vec().iter().any(contains_map_literal)
+    }
+    Expr::List(list) => list
+      .clone()
+      .into_iter()
+      .any(|expr| contains_map_literal(&expr)),
+    _ => false,
+  }
+}
+
+fn as_symbol(expr: &Expr) -> Result<Symbol, EvalError> {
+  match expr {
+    Expr::Atom(Atom::Symbol(symbol)) => Ok(symbol.clone()),
+    _ => Err(EvalError::InvalidType),
+  }
+}
+
+fn as_list(expr: &Expr) -> Result<List, EvalError> {
+  match expr {
+    Expr::List(list) => Ok(list.clone()),
+    _ => Err(EvalError::InvalidType),
+  }
+}
@@ -1,7 +1,15 @@
 use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
 
-use zuko::ast::{Atom, Expr};
-use zuko::{eval, read};
+use zuko::ast::{Arity, Atom, Expr, FromExpr, IntoExpr, Port};
+use zuko::env::{self, Capabilities, Capability, FsAccess};
+use zuko::eval::{EvalError, Evaluator};
+use zuko::read::ReadError;
+use zuko::{check, eval, read};
 
 #[test]
 pub fn fibonacci() {
@@ -32,3 +40,1012 @@ pub fn square_root() {
 
   assert_eq!(eval_expr, Expr::Atom(Atom::Number(2.0000000929222947)))
 }
+
+#[test]
+pub fn lexical_scope() {
+  let source = fs::read_to_string("tests/lexical-scope.zuko").unwrap();
+
+  let read_expr = read::read(&source).unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(7.0)))
+}
+
+#[test]
+pub fn free_globals() {
+  let mut evaluator = Evaluator::new();
+
+  let source = "(define x 1)\n\
+                (define y (begin (define z 2) (undefined-function x)))";
+
+  let read_expr = read::read(source).unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::UndefinedSymbol(_)));
+
+  // Forms referencing an undefined global aren't evaluated at all.
+  let read_expr = read::read("x").unwrap();
+  let eval_expr = evaluator.eval_expr(read_expr).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(1.0)));
+
+  let read_expr = read::read("z").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::UndefinedSymbol(_)));
+  // A define that never runs doesn't hide the binding further out.
+  let source = "(if #f (define abs 1) 0)\n\
+                (define f (function (x) (if #f (define abs 1) (abs x))))\n\
+                (+ (abs (- 0 2)) (f (- 0 3)))";
+  let read_expr = read::read(source).unwrap();
+  let eval_expr = evaluator.eval_expr(read_expr).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(5.0)));
+}
+
+#[test]
+pub fn macro_arguments() {
+  let mut evaluator = Evaluator::new();
+
+  // Arguments to macros aren't resolved, so they can name undefined globals.
+  let source = "(define bind (macro (terms) (cons (quote define) terms)))\n\
+                (bind q 4)\n\
+                q";
+
+  let read_expr = read::read(source).unwrap();
+  let eval_expr = evaluator.eval_expr(read_expr).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(4.0)));
+
+  let source = "(define my-quote (macro (terms) (cons (quote quote) terms)))\n\
+                (my-quote undefined-name)";
+
+  let read_expr = read::read(source).unwrap();
+  let eval_expr = evaluator.eval_expr(read_expr).unwrap();
+  assert_eq!(eval_expr.to_string(), "undefined-name");
+
+  let read_expr = read::read("(my-quote (define))").unwrap();
+  assert!(evaluator.eval_expr(read_expr).is_ok());
+
+  // Calls to functions still fail on undefined arguments.
+  let read_expr = read::read("(print undefined-name)").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::UndefinedSymbol(_)));
+}
+
+#[test]
+pub fn runtime_evaluation() {
+  let source = "(define environment (new-environment))\n\
+                (eval (quote (define x 2)) environment)\n\
+                (define x 3)\n\
+                (load \"tests/modules/geometry.zuko\")\n\
+                (+ (eval (read-string \"(* x 10)\") environment) (square x))";
+
+  let read_expr = read::read(source).unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(29.0)))
+}
+
+#[test]
+pub fn check() {
+  let source = "(define square (function (x) (* x x)))\n\
+                (square 1 2)\n\
+                (if (square 2) y)";
+
+  let read_expr = read::read(source).unwrap();
+  let warnings: Vec<String> = check::check(&read_expr)
+    .into_iter()
+    .map(|warning| warning.to_string())
+    .collect();
+
+  assert_eq!(
+    warnings,
+    vec![
+      "2:1: arity is wrong, expected 1 arguments but found 2",
+      "3:1: 'if' expects a condition and two branches",
+      "3:16: 'y' is undefined",
+    ]
+  )
+}
+
+#[test]
+pub fn modules() {
+  let mut evaluator = Evaluator::new();
+
+  let eval_expr = evaluator.eval_file("tests/modules/main.zuko").unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(21.0)));
+
+  // Only exported bindings are visible.
+  let read_expr = read::read("secret").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::UndefinedSymbol(_)));
+
+  let error = evaluator
+    .eval_file("tests/modules/main-cycle.zuko")
+    .unwrap_err();
+  assert!(matches!(error, EvalError::CircularImport(_)));
+}
+
+#[test]
+pub fn natives() {
+  let source = "(define offset 10)\n\
+                (define sorted\n\
+                  (sort (list 3 1 2) (function (a b) (< (+ a offset) (+ b offset)))))\n\
+                (if (= (gensym) (gensym)) 0 (reduce sorted (function (x acc) (+ (* acc 10) x)) 0))";
+
+  let read_expr = read::read(source).unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(123.0)));
+
+  let read_expr = read::read("(sort (list 1))").unwrap();
+  let error = eval::eval(read_expr.clone()).unwrap_err();
+  assert!(matches!(error, EvalError::WrongArity));
+
+  let warnings: Vec<String> = check::check(&read_expr)
+    .into_iter()
+    .map(|warning| warning.to_string())
+    .collect();
+  assert_eq!(
+    warnings,
+    vec!["1:1: arity is wrong, expected 2 arguments but found 1"]
+  );
+}
+
+#[test]
+pub fn builder() {
+  let mut evaluator = Evaluator::builder()
+    .without_stdlib()
+    .native("double", Arity::Exact(1), |_, arguments| {
+      match arguments.first() {
+        Some(Expr::Atom(Atom::Number(number))) => {
+          Ok(Expr::Atom(Atom::Number(number * 2.0)))
+        }
+        _ => Err(EvalError::InvalidType),
+      }
+    })
+    .preload("tests/modules/geometry.zuko")
+    .max_depth(50)
+    .build()
+    .unwrap();
+
+  let read_expr = read::read("(double (square 3))").unwrap();
+  let eval_expr = evaluator.eval_expr(read_expr).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(18.0)));
+
+  // The standard library was skipped.
+  let read_expr = read::read("(abs 1)").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::UndefinedSymbol(_)));
+
+  let read_expr =
+    read::read("(define loop (function (n) (loop n))) (loop 1)").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::StackOverflow));
+
+  let error = Evaluator::builder().stdlib("(").build().err().unwrap();
+  assert!(matches!(error, EvalError::Read(_)));
+
+  // Without the prelude, the bundled standard library is skipped too.
+  let mut evaluator = Evaluator::builder()
+    .without_prelude()
+    .function("add", |x: f64, y: f64| x + y)
+    .build()
+    .unwrap();
+  let read_expr = read::read("(add 1 2)").unwrap();
+  let eval_expr = evaluator.eval_expr(read_expr).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(3.0)));
+
+  let read_expr = read::read("(cons 1 ())").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::UndefinedSymbol(_)));
+
+  let evaluator = Evaluator::builder()
+    .without_prelude()
+    .stdlib("(define one 1)")
+    .build()
+    .unwrap();
+  assert!(evaluator.get_global("one").is_some());
+}
+
+#[test]
+pub fn conversions() {
+  let mut evaluator = Evaluator::builder()
+    .function("hypot", |x: f64, y: f64| x.hypot(y))
+    .function("repeat", |string: String, count: usize| {
+      string.repeat(count)
+    })
+    .function("split", |number: i64| (number / 10, number % 10))
+    .function("sum", |numbers: Vec<f64>| numbers.iter().sum::<f64>())
+    .function("not", |bool: bool| !bool)
+    .build()
+    .unwrap();
+
+  let read_expr = read::read(
+    "(+ (hypot 3 4) (sum (cons (head (split 42)) (tail (split 42)))))",
+  )
+  .unwrap();
+  let eval_expr = evaluator.eval_expr(read_expr).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(11.0)));
+
+  let read_expr = read::read("(repeat \"ab\" 2)").unwrap();
+  let eval_expr = evaluator.eval_expr(read_expr).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::String("abab".into())));
+
+  let read_expr = read::read("(repeat \"ab\" 1.5)").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::InvalidType));
+
+  let read_expr = read::read("(hypot 3)").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::WrongArity));
+
+  let read_expr = read::read("(not #f)").unwrap();
+  let eval_expr = evaluator.eval_expr(read_expr).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Bool(true)));
+
+  // Only booleans convert to bool, rather than anything by its truthiness.
+  let read_expr = read::read("(not 1)").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::InvalidType));
+
+  let expr = vec![Some(1), None].into_expr();
+  assert_eq!(expr.to_string(), "(1 #f)");
+  assert_eq!(
+    Vec::<Option<u8>>::from_expr(expr).unwrap(),
+    vec![Some(1), None]
+  );
+}
+
+#[test]
+pub fn calls() {
+  let mut evaluator = Evaluator::new();
+  evaluator.set_global("scale", Expr::Atom(Atom::Number(3.0)));
+
+  let source = "(define handler (function (x) (* x scale)))\n\
+                (define twice (macro (terms) (cons (quote +) (cons (head terms) terms))))";
+  let read_expr = read::read(source).unwrap();
+  evaluator.eval_expr(read_expr).unwrap();
+
+  let handler = evaluator.get_global("handler").unwrap();
+  let eval_expr = evaluator.call(&handler, vec![2.into_expr()]).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(6.0)));
+
+  let twice = evaluator.get_global("twice").unwrap();
+  let eval_expr = evaluator.call(&twice, vec![5.into_expr()]).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(10.0)));
+
+  let sqrt = evaluator.get_global("sqrt").unwrap();
+  let eval_expr = evaluator.call(&sqrt, vec![16.into_expr()]).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(4.0)));
+
+  let error = evaluator.call(&handler, vec![]).unwrap_err();
+  assert!(matches!(error, EvalError::WrongArity));
+
+  assert!(evaluator.get_global("missing").is_none());
+}
+
+#[test]
+pub fn limits() {
+  let mut evaluator = Evaluator::builder().fuel(10_000).build().unwrap();
+
+  let source = "(define spin (function (n) (if (= n 0) 0 (spin (- n 1)))))";
+  let read_expr = read::read(source).unwrap();
+  evaluator.eval_expr(read_expr).unwrap();
+
+  let read_expr = read::read("(spin 100)").unwrap();
+  let eval_expr = evaluator.eval_expr(read_expr.clone()).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(0.0)));
+
+  // The budget is shared until it is reset.
+  for _ in 0..20 {
+    if let Err(error) = evaluator.eval_expr(read_expr.clone()) {
+      assert!(matches!(error, EvalError::FuelExhausted));
+      break;
+    }
+  }
+  assert!(evaluator.steps() > 10_000);
+
+  evaluator.reset_budget();
+  evaluator.eval_expr(read_expr).unwrap();
+
+  let mut evaluator =
+    Evaluator::builder().max_allocations(100).build().unwrap();
+
+  let read_expr = read::read("(range 0 1000)").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::MemoryLimit));
+
+  // Allocations by other evaluators don't count against this one.
+  let mut evaluator =
+    Evaluator::builder().max_allocations(5000).build().unwrap();
+  let mut other = Evaluator::new();
+  for _ in 0..20 {
+    let read_expr = read::read("(range 0 200)").unwrap();
+    other.eval_expr(read_expr).unwrap();
+  }
+  assert_eq!(evaluator.allocations(), 0);
+
+  let read_expr = read::read("(+ 1 1)").unwrap();
+  evaluator.eval_expr(read_expr).unwrap();
+}
+
+#[test]
+pub fn default_max_depth() {
+  // Runs on a stack the size of the main thread's.
+  let thread = thread::Builder::new().stack_size(8 << 20).spawn(|| {
+    let mut evaluator = Evaluator::builder().fuel(10_000_000).build().unwrap();
+
+    let source = "(define f (function (n) (+ 1 (f n))))\n\
+                  (f 1)";
+    let read_expr = read::read(source).unwrap();
+    let error = evaluator.eval_expr(read_expr).unwrap_err();
+    assert!(matches!(error, EvalError::StackOverflow));
+
+    let source = "(define m (macro (terms) (quote (m))))\n\
+                  (m)";
+    let read_expr = read::read(source).unwrap();
+    let error = evaluator.eval_expr(read_expr).unwrap_err();
+    assert!(matches!(error, EvalError::StackOverflow));
+  });
+  thread.unwrap().join().unwrap();
+}
+
+#[test]
+pub fn interrupts() {
+  // Takes exponential time without recursing deeply.
+  let source = "(define spin (function (n) (if (= n 0) 0 (+ (spin (- n 1)) (spin (- n 1))))))";
+
+  let mut evaluator = Evaluator::builder()
+    .timeout(Duration::from_millis(50))
+    .build()
+    .unwrap();
+  evaluator.eval_expr(read::read(source).unwrap()).unwrap();
+
+  let read_expr = read::read("(spin 40)").unwrap();
+  let error = evaluator.eval_expr(read_expr.clone()).unwrap_err();
+  assert!(matches!(error, EvalError::Timeout));
+
+  // Time spent between evaluations doesn't count.
+  thread::sleep(Duration::from_millis(100));
+  let eval_expr = evaluator.eval_expr(read::read("(spin 8)").unwrap());
+  assert!(eval_expr.is_ok());
+
+  let mut evaluator = Evaluator::new();
+  evaluator.eval_expr(read::read(source).unwrap()).unwrap();
+
+  let interrupt = evaluator.interrupt_handle();
+  let handle = thread::spawn(move || {
+    thread::sleep(Duration::from_millis(50));
+    interrupt.store(true, Ordering::Relaxed);
+  });
+
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::Interrupted));
+  handle.join().unwrap();
+}
+
+#[test]
+pub fn capabilities() {
+  let mut evaluator = Evaluator::builder()
+    .capabilities(Capabilities {
+      fs: FsAccess::read_only().scoped("tests/modules"),
+      ..Capabilities::none()
+    })
+    .build()
+    .unwrap();
+
+  let read_expr = read::read("(load \"tests/modules/geometry.zuko\")").unwrap();
+  evaluator.eval_expr(read_expr).unwrap();
+
+  let read_expr = read::read("(load \"tests/fibonacci.zuko\")").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(
+    error,
+    EvalError::PermissionDenied(Capability::FsRead)
+  ));
+
+  let read_expr = read::read("(print (square 2))").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert_eq!(error.to_string(), "console access is denied");
+}
+
+#[test]
+pub fn garbage_collection() {
+  let frames = env::live_frames();
+
+  let mut evaluator = Evaluator::new();
+  let source = "(define make-counter\n\
+                  (function (n)\n\
+                    (begin\n\
+                      (define count (function (n) (if (= n 0) 0 (count (- n 1)))))\n\
+                      (count n))))\n\
+                (define fibonacci\n\
+                  (function (n) (if (< n 2) n (+ (fibonacci (- n 1)) (fibonacci (- n 2))))))\n\
+                (make-counter 10)";
+
+  for _ in 0..5000 {
+    let read_expr = read::read(source).unwrap();
+    evaluator.eval_expr(read_expr).unwrap();
+  }
+  // Each call to make-counter leaves behind a cycle, which would add up to
+  // 5000 frames without collection.
+  assert!(env::live_frames() < frames + 2500);
+
+  // Calls from Rust collect garbage too.
+  let make_counter = evaluator.get_global("make-counter").unwrap();
+  let frames_before_calls = env::live_frames();
+  for _ in 0..5000 {
+    evaluator.call(&make_counter, vec![10.into_expr()]).unwrap();
+  }
+  assert!(env::live_frames() < frames_before_calls + 2500);
+  drop(make_counter);
+
+  // Values still referenced from Rust survive collection.
+  let fibonacci = evaluator.get_global("fibonacci").unwrap();
+  evaluator.collect_garbage();
+  let eval_expr = evaluator.call(&fibonacci, vec![10.into_expr()]).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(55.0)));
+
+  drop(evaluator);
+  drop(fibonacci);
+  env::collect();
+  assert_eq!(env::live_frames(), frames);
+}
+
+#[test]
+pub fn vectors() {
+  let source = fs::read_to_string("tests/vectors.zuko").unwrap();
+
+  let read_expr = read::read(&source).unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(42.0)));
+
+  let read_expr = read::read("(vector-ref (vector 1 2) 2)").unwrap();
+  let error = eval::eval(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::OutOfBounds(2)));
+
+  let read_expr = read::read("(quote [1 [a \"b\"]])").unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(eval_expr.to_string(), "[1 [a \"b\"]]");
+}
+
+#[test]
+pub fn maps() {
+  let source = fs::read_to_string("tests/maps.zuko").unwrap();
+
+  let read_expr = read::read(&source).unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(41.0)));
+
+  let read_expr =
+    read::read("(map-get (hash-map 1 2 (/ 0 (- 0 1)) 3) 0)").unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(3.0)));
+
+  let read_expr = read::read("(map-put {} [1] 2)").unwrap();
+  let error = eval::eval(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::InvalidKey));
+
+  assert!(read::read("{1 2 3}").is_err());
+
+  let read_expr = read::read("(quote {a \"b\"})").unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(eval_expr.to_string(), "{a \"b\"}");
+
+  // Keys are evaluated, so keys that look the same can differ, and entries
+  // are evaluated in the order they are written.
+  let read_expr = read::read(
+    r#"(list (map-size {(gensym) 1 (gensym) 2})
+             (with-output-to-string
+               (function ()
+                 {:a (display "a") :b (display "b") :c (display "c") :d (display "d")})))"#,
+  )
+  .unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(eval_expr.to_string(), r#"(2 "abcd")"#);
+
+  let read_expr = read::read("{a 1}").unwrap();
+  let error = eval::eval(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::UndefinedSymbol(_)));
+
+  // Many keys spread the trie over several levels.
+  let read_expr = read::read(
+    "(define squares (reduce (range 0 100)
+                             (function (x map) (map-put map x (* x x)))
+                             {}))
+     (+ (map-size squares)
+        (+ (map-get squares 99)
+           (map-size (reduce (range 0 50)
+                             (function (x map) (map-remove map x))
+                             squares))))",
+  )
+  .unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(eval_expr, Expr::Atom(Atom::Number(100.0 + 9801.0 + 50.0)));
+}
+
+#[test]
+pub fn booleans() {
+  let read_expr = read::read(
+    "(define yes #t)
+     (list yes false (= 1 1) (< 2 1) (boolean? #f) (boolean? ()) (= #f ()))",
+  )
+  .unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(eval_expr.to_string(), "(#t #f #t #f #t #f #f)");
+
+  let read_expr =
+    read::read("(list (if #f 1 2) (if () 1 2) (if 0 1 2) (if \"\" 1 2))")
+      .unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(eval_expr.to_string(), "(2 2 1 1)");
+
+  assert!(!bool::from_expr(Expr::Atom(Atom::Bool(false))).unwrap());
+  assert_eq!(true.into_expr(), Expr::Atom(Atom::Bool(true)));
+
+  assert!(read::read("#x").is_err());
+  assert!(read::read("#tf").is_err());
+}
+
+#[test]
+pub fn strings() {
+  let read_expr = read::read(r#""a\"b\\c\n\td\u{1F600}\u{7}""#).unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(
+    eval_expr,
+    Expr::Atom(Atom::String("a\"b\\c\n\td\u{1F600}\u{7}".to_string()))
+  );
+  assert_eq!(eval_expr.to_string(), r#""a\"b\\c\n\td😀\u{7}""#);
+
+  // Printed strings read back the same.
+  let read_expr = read::read(&eval_expr.to_string()).unwrap();
+  assert_eq!(eval::eval(read_expr).unwrap(), eval_expr);
+
+  let read_expr = read::read("#r\"C:\\path\nnext\"").unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(
+    eval_expr,
+    Expr::Atom(Atom::String("C:\\path\nnext".to_string()))
+  );
+
+  let read_expr = read::read(r###"#r##"say "hi"# "#there"##"###).unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(
+    eval_expr,
+    Expr::Atom(Atom::String(r##"say "hi"# "#there"##.to_string()))
+  );
+
+  assert!(read::read(r#""\q""#).is_err());
+  assert!(read::read(r#""\u{110000}""#).is_err());
+  assert!(read::read(r##"#r#"open""##).is_err());
+}
+
+#[test]
+pub fn symbols() {
+  let read_expr = read::read(
+    r"(define *point2D* 3)
+     (define set-HTTP-header! (function (x) (+ x 1)))
+     (define |hello world| 10)
+     (list (set-HTTP-header! *point2D*) |hello world| (<= 2 2) (>= 1 2)
+           -4 +1.5 -.5 (- 3 1) (quote (a->b x.y <=> |a\|b| |if| |1x|)))",
+  )
+  .unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(
+    eval_expr.to_string(),
+    r"(4 10 #t #f -4 1.5 -0.5 2 (a->b x.y <=> |a\|b| |if| |1x|))"
+  );
+
+  assert!(matches!(
+    read::read("1abc"),
+    Err(ReadError::InvalidNumber(_))
+  ));
+  assert!(matches!(
+    read::read("1.2.3"),
+    Err(ReadError::InvalidNumber(_))
+  ));
+  assert!(matches!(
+    read::read("a#b"),
+    Err(ReadError::UnexpectedChar('#'))
+  ));
+  assert!(read::read("|open").is_err());
+
+  // The empty symbol needs bars to be read back.
+  let read_expr = read::read(r#"(string->symbol "")"#).unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(eval_expr.to_string(), "||");
+  let read_expr = read::read("(quote ||)").unwrap();
+  assert_eq!(eval::eval(read_expr).unwrap(), eval_expr);
+}
+
+#[test]
+pub fn keywords() {
+  let read_expr = read::read(
+    r#"(define options {:width 80 :name "zuko"})
+       (list :width (map-get options :width) (keyword? :name) (keyword? (quote name))
+             (keyword->string :name) (= (string->keyword "name") :name)
+             (string->keyword "two words"))"#,
+  )
+  .unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(
+    eval_expr.to_string(),
+    r#"(:width 80 #t #f "name" #t :|two words|)"#
+  );
+
+  assert!(read::read(":").is_err());
+  assert!(read::read("(:)").is_err());
+
+  // The empty keyword is printed with bars, which can be read back.
+  let read_expr =
+    read::read(r#"(list (string->keyword "") (= (string->keyword "") :||))"#)
+      .unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(eval_expr.to_string(), "(:|| #t)");
+}
+
+#[test]
+pub fn chars() {
+  let read_expr = read::read(
+    r#"(list #\a #\space #\newline #\( #\x41 #\é (string-ref "héllo" 1)
+             (char->integer #\a) (integer->char 955) (char-upcase #\ß)
+             (char-upcase #\a) (char-alphabetic? #\1) (char-numeric? #\1)
+             (char? #\a) (char? "a") (= #\a (string-ref "abc" 0)) #\x7)"#,
+  )
+  .unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(
+    eval_expr.to_string(),
+    r"(#\a #\space #\newline #\( #\A #\é #\é 97 #\λ #\ß #\A #f #t #t #f #t #\x7)"
+  );
+
+  let read_expr = read::read(r#"(string-ref "abc" 3)"#).unwrap();
+  let error = eval::eval(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::OutOfBounds(3)));
+
+  assert!(matches!(
+    read::read(r"#\bogus"),
+    Err(ReadError::InvalidChar(_))
+  ));
+}
+
+#[test]
+pub fn string_library() {
+  let read_expr = read::read(
+    r#"(list (string-length "héllo") (string-append "a" "b" "c") (string-append)
+             (substring "héllo" 1 3) (substring "héllo" 2)
+             (string-split "a,b,,c" ",") (string-split "hé" "")
+             (string-join (list "a" "b") ", ") (string-trim "  hi ")
+             (string-upcase "straße") (string-downcase "ABC")
+             (string-contains? "hello" "ell") (string-replace "a-b-c" "-" "+")
+             (string->number "-1.5") (string->number "1x") (number->string 42)
+             (symbol->string (quote abc)) (string->symbol "a b")
+             (string->list "hé") (list->string (list #\o #\k)))"#,
+  )
+  .unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(
+    eval_expr.to_string(),
+    r#"(5 "abc" "" "él" "llo" ("a" "b" "" "c") ("h" "é") "a, b" "hi" "STRASSE" "abc" #t "a+b+c" -1.5 #f "42" "abc" |a b| (#\h #\é) "ok")"#
+  );
+
+  let read_expr = read::read(r#"(substring "abc" 2 4)"#).unwrap();
+  let error = eval::eval(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::OutOfBounds(4)));
+
+  let read_expr = read::read(r#"(substring "abc" 2 1)"#).unwrap();
+  let error = eval::eval(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::OutOfBounds(2)));
+}
+
+#[test]
+pub fn formatting() {
+  let read_expr = read::read(r#"(format #f "~q")"#).unwrap();
+  let error = eval::eval(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::InvalidFormat(_)));
+
+  let read_expr = read::read(
+    r#"(format #f "~a and ~s~%~5d|~3a|~.2f|~7.1f ~~" "hi" "hi" 42 #\x 3.14159 -2)"#,
+  )
+  .unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(
+    eval_expr,
+    Expr::Atom(Atom::String(
+      "hi and \"hi\"\n   42|x  |3.14|   -2.0 ~".to_string()
+    ))
+  );
+
+  let read_expr = read::read(r#"(format #f "~a ~a" 1)"#).unwrap();
+  let error = eval::eval(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::WrongArity));
+
+  // Widths are limited, so that they can't allocate without bound.
+  let read_expr = read::read(r#"(format #f "~100000000000a" 1)"#).unwrap();
+  let error = eval::eval(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::InvalidFormat(_)));
+
+  let read_expr = read::read(r#"(format #f "~.5000f" 1)"#).unwrap();
+  let error = eval::eval(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::InvalidFormat(_)));
+
+  let read_expr = read::read(r#"(format #f "~d" "1")"#).unwrap();
+  let error = eval::eval(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::InvalidType));
+
+  let read_expr =
+    read::read(r#"(quote ("a\nb" #\c [#\d "e"] {:k "v"} sym 1))"#).unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(eval_expr.to_display_string(), "(a\nb c [d e] {:k v} sym 1)");
+  assert_eq!(
+    eval_expr.to_string(),
+    r#"("a\nb" #\c [#\d "e"] {:k "v"} sym 1)"#
+  );
+
+  let mut evaluator = Evaluator::builder()
+    .capabilities(Capabilities::none())
+    .build()
+    .unwrap();
+  let read_expr = read::read(r#"(display "hi")"#).unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(
+    error,
+    EvalError::PermissionDenied(Capability::Console)
+  ));
+
+  // Closing stdin would close it for every evaluator on the thread.
+  let read_expr = read::read(
+    "(close-port (current-input-port))
+     (input-port? (current-input-port))",
+  )
+  .unwrap();
+  eval::eval(read_expr).unwrap();
+  assert!(!Port::stdin().is_closed());
+}
+
+#[test]
+pub fn files() {
+  let directory = std::env::temp_dir().join("zuko-files-test");
+  let _ = fs::remove_dir_all(&directory);
+  fs::create_dir_all(&directory).unwrap();
+
+  let mut evaluator = Evaluator::builder()
+    .capabilities(Capabilities {
+      fs: FsAccess::read_write().scoped(&directory),
+      ..Capabilities::none()
+    })
+    .build()
+    .unwrap();
+  evaluator.set_global("dir", directory.to_str().unwrap().into_expr());
+
+  let read_expr = read::read(
+    r#"(define path (string-append dir "/notes.txt"))
+       (define before (file-exists? path))
+       (write-file path "one\n")
+       (append-file path "two\n")
+       (list before (file-exists? path) (read-file path) (read-lines path)
+             (list-directory dir)
+             (begin (delete-file path) (file-exists? path))
+             (try (function () (read-file path))
+                  (function (error) (string? error)))
+             (try (function () (read-file "/etc/hostname"))
+                  (function (error) error)))"#,
+  )
+  .unwrap();
+  let eval_expr = evaluator.eval_expr(read_expr).unwrap();
+  assert_eq!(
+    eval_expr.to_string(),
+    r#"(#f #t "one\ntwo\n" ("one" "two") ("notes.txt") #f #t "filesystem read access is denied")"#
+  );
+
+  // Limits can't be caught.
+  let mut evaluator = Evaluator::builder().fuel(1000).build().unwrap();
+  let read_expr = read::read(
+    "(define spin (function () (spin)))
+     (try spin (function (error) 0))",
+  )
+  .unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::FuelExhausted));
+
+  fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+pub fn ports() {
+  let read_expr = read::read(
+    r#"(define in (open-input-string "(a [1 2]) sym\nnext line\nhé"))
+       (define out (open-output-string))
+       (write-string "x=" out)
+       (write (read in) out)
+       (display " " out)
+       (write (read in) out)
+       (newline out)
+       (list (get-output-string out) (read-line in) (read-line in)
+             (peek-char in) (read-char in) (read-char in)
+             (eof-object? (read-char in)) (eof-object? (read-line in))
+             (eof-object? (read in)) (input-port? in) (output-port? in)
+             (with-output-to-string
+               (function ()
+                 (begin (display "hi ")
+                        (write-string "s ")
+                        (format #t "~a" 42)
+                        (print "p")))))"#,
+  )
+  .unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(
+    eval_expr.to_string(),
+    r#"("x=(a [1 2]) sym\n" "" "next line" #\h #\h #\é #t #t #t #t #f "hi s 42\"p\"\n")"#
+  );
+
+  let read_expr = read::read(
+    r#"(define in (open-input-string "x"))
+       (close-port in)
+       (read-char in)"#,
+  )
+  .unwrap();
+  let error = eval::eval(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::PortClosed));
+
+  let read_expr = read::read(
+    r#"(define out (open-output-string))
+       (format out "~a~%" "hello")
+       (get-output-string out)"#,
+  )
+  .unwrap();
+  let eval_expr = eval::eval(read_expr).unwrap();
+  assert_eq!(eval_expr, "hello\n".into_expr());
+
+  let read_expr = read::read(r#"(format 1 "hello")"#).unwrap();
+  let error = eval::eval(read_expr).unwrap_err();
+  assert!(matches!(error, EvalError::InvalidType));
+
+  let path = std::env::temp_dir().join("zuko-ports-test.txt");
+  let mut evaluator = Evaluator::new();
+  evaluator.set_global("path", path.to_str().unwrap().into_expr());
+
+  let read_expr = read::read(
+    r#"(define out (open-output-file path))
+       (display "line one" out)
+       (newline out)
+       (close-port out)
+       (define in (open-input-file path))
+       (read-line in)"#,
+  )
+  .unwrap();
+  let eval_expr = evaluator.eval_expr(read_expr).unwrap();
+  assert_eq!(eval_expr, "line one".into_expr());
+
+  // Output to string ports doesn't need the console.
+  let mut evaluator = Evaluator::builder()
+    .capabilities(Capabilities::none())
+    .build()
+    .unwrap();
+  let read_expr =
+    read::read(r#"(with-output-to-string (function () (display 1)))"#).unwrap();
+  let eval_expr = evaluator.eval_expr(read_expr).unwrap();
+  assert_eq!(eval_expr, "1".into_expr());
+
+  fs::remove_file(path).unwrap();
+}
+
+#[test]
+pub fn stdin() {
+  let mut child = Command::new(env!("CARGO_BIN_EXE_zuko"))
+    .arg("tests/greet.zuko")
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .unwrap();
+  child
+    .stdin
+    .take()
+    .unwrap()
+    .write_all("Zuko\n(1 2\n 3)\n".as_bytes())
+    .unwrap();
+
+  let output = child.wait_with_output().unwrap();
+  assert!(output.status.success());
+  assert_eq!(
+    String::from_utf8(output.stdout).unwrap(),
+    "Hello, Zuko! 6\n(\"\" #t #t)\n"
+  );
+
+  let mut evaluator = Evaluator::builder()
+    .capabilities(Capabilities::none())
+    .build()
+    .unwrap();
+  let read_expr = read::read("(read-line)").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(
+    error,
+    EvalError::PermissionDenied(Capability::Console)
+  ));
+}
+
+#[test]
+pub fn process() {
+  let output = Command::new(env!("CARGO_BIN_EXE_zuko"))
+    .args(["tests/script.zuko", "one", "two"])
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(3));
+  assert_eq!(
+    String::from_utf8(output.stdout).unwrap(),
+    "(\"one\" \"two\")\n"
+  );
+
+  let mut evaluator = Evaluator::builder()
+    .arguments(vec!["a", "b"])
+    .build()
+    .unwrap();
+  let mut eval = |source: &str| {
+    let read_expr = read::read(source).unwrap();
+    evaluator.eval_expr(read_expr)
+  };
+
+  assert_eq!(
+    eval("(command-line-arguments)").unwrap().to_string(),
+    "(\"a\" \"b\")"
+  );
+  assert!(matches!(eval("(exit 2)"), Err(EvalError::Exit(2))));
+  assert!(matches!(eval("(exit 256)"), Err(EvalError::InvalidType)));
+  assert!(matches!(eval("(exit -1)"), Err(EvalError::InvalidType)));
+  assert!(matches!(
+    eval("(try (function () (exit)) (function (error) 1))"),
+    Err(EvalError::Exit(0))
+  ));
+
+  eval("(setenv \"ZUKO_TEST_VARIABLE\" \"value\")").unwrap();
+  assert_eq!(
+    eval("(getenv \"ZUKO_TEST_VARIABLE\")").unwrap().to_string(),
+    "\"value\""
+  );
+  assert_eq!(
+    eval("(getenv \"ZUKO_UNSET_VARIABLE\")")
+      .unwrap()
+      .to_string(),
+    "#f"
+  );
+
+  // Invalid names and values are errors, which can be caught.
+  for source in [
+    r#"(setenv "" "x")"#,
+    r#"(setenv "A=B" "x")"#,
+    r#"(setenv "A" "x\0y")"#,
+  ] {
+    assert!(matches!(eval(source), Err(EvalError::Io(_))));
+  }
+  assert_eq!(
+    eval(r#"(try (function () (setenv "" "x")) (function (error) 1))"#)
+      .unwrap(),
+    1.into_expr()
+  );
+  assert_eq!(
+    String::from_expr(eval("(current-directory)").unwrap()).unwrap(),
+    std::env::current_dir().unwrap().to_string_lossy()
+  );
+
+  assert_eq!(
+    eval("(run-process \"sh\" \"-c\" \"echo out; echo err >&2; exit 4\")")
+      .unwrap()
+      .to_string(),
+    "{:status 4 :stdout \"out\\n\" :stderr \"err\\n\"}"
+  );
+
+  let mut evaluator = Evaluator::builder()
+    .capabilities(Capabilities::none())
+    .build()
+    .unwrap();
+  let read_expr = read::read("(run-process \"true\")").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(
+    error,
+    EvalError::PermissionDenied(Capability::Process)
+  ));
+  let read_expr = read::read("(getenv \"HOME\")").unwrap();
+  let error = evaluator.eval_expr(read_expr).unwrap_err();
+  assert!(matches!(
+    error,
+    EvalError::PermissionDenied(Capability::Env)
+  ));
+}